@@ -1,11 +1,55 @@
-from threading import Thread 
-import socket 
+from threading import Thread
+import json
+import socket
 
 CONN = ("127.0.0.1", 8080)
 
+# Version of the protocol spoken (see msg_templates::shared::PROTOCOL_VERSION)
+PROTOCOL_VERSION = 1
+CAPABILITIES = ["history", "receipts"]
+
+# Speaks the JSON codec: one JSON object per line, with the type of the package in
+# its "type" key
+def send_pkg(sock, pkg):
+    sock.sendall(json.dumps(pkg).encode("utf-8") + b"\n")
+
+def recv_pkg(reader):
+    line = reader.readline()
+    if not line:
+        return None
+    return json.loads(line)
+
+# Sends the hello package and waits for the welcome, returns None if the server
+# refused the connection
+def handshake(sock, reader):
+    send_pkg(sock, {
+        "type": "command",
+        "command": "hello",
+        "args": [],
+        "version": PROTOCOL_VERSION,
+        "name": "client.py",
+        "software_version": "0.1.0",
+        "capabilities": CAPABILITIES,
+    })
+    welcome = recv_pkg(reader)
+    if welcome is None or welcome.get("command") != "welcome":
+        print(f"The server refused the connection: {welcome}")
+        return None
+    if welcome.get("version") != PROTOCOL_VERSION:
+        print(f"The server speaks the version {welcome.get('version')} of the protocol")
+        return None
+    return welcome
+
 def main():
     server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
     server.connect(CONN)
+    reader = server.makefile("rb")
+
+    welcome = handshake(server, reader)
+    if welcome is None:
+        server.close()
+        return
+    print(f"Connected to {welcome.get('name')} {welcome.get('software_version')}")
 
     Thread(target=sender, args=[server]).start()
 
@@ -13,11 +57,11 @@ def main():
     count = 0
     print("Listening")
     while True:
-        msg = server.recv(1024)
-        if not msg:
+        msg = recv_pkg(reader)
+        if msg is None:
             print("EMPTY PACKET")
             break
-        print(f"Message received {count}: {msg.decode('utf-8')}")
+        print(f"Message received {count}: {msg}")
         count += 1
 
 
@@ -26,19 +70,19 @@ def main():
 def sender(sock):
     while True:
         ui = input("SEND ME? ")
-        
+
         if ui == "killme":
             print("Intentionally sending invalid packages...")
-            sock.send(b"killmekillmekillme");
+            sock.sendall(b"killmekillmekillme\n");
 
         elif ui == "dm":
             print("Sending direct message")
-            sock.send(b"type=dmsg:id=2:msg=This is a direct message:")
+            send_pkg(sock, {"type": "direct_message", "id": 2, "msg": "This is a direct message"})
 
         else:
-            ui = f"type=msg:msg={ui}:"
-            print(f"Sending message {ui} to user")
-            sock.send(ui.encode("utf-8"))
+            pkg = {"type": "message", "msg": ui}
+            print(f"Sending message {pkg} to user")
+            send_pkg(sock, pkg)
 
 if __name__ == "__main__":
-    main()
\ No newline at end of file
+    main()
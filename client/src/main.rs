@@ -1,33 +1,127 @@
 use msg_templates;
-use std::io::{Read, Write};
+use msg_templates::codec::{self, Codec};
+use msg_templates::framing;
+use msg_templates::messages::{ClientMessage, ServerMessage};
+use msg_templates::shared::{self, capability};
+use msg_templates::Lnp;
+use std::io::Write;
 use std::{io, net, thread};
 
+mod render;
 mod syntax;
 #[cfg(test)]
 mod test; // TODO: Pass this to `/tests/` folder at the root of the project
 
 const SERVER: &str = "127.0.0.1:8080";
-const BUFF_SIZE: usize = 1024;
+const MAX_MESSAGE_SIZE: usize = framing::DEFAULT_MAX_FRAME_SIZE;
+const USAGE: &str = "Usage: client [--server <addr>] [--nick <nickname>] [--codec <lnpkg|json>]";
+
+/// Options given in the command line
+#[derive(Debug)]
+pub struct Args {
+    /// Address of the server to connect to
+    pub server: String,
+    /// Nickname requested to the server in the hello
+    pub nick: Option<String>,
+    /// Codec spoken with the server (*see `msg_templates::codec`*)
+    pub codec: &'static dyn Codec,
+}
+
+/// Parses the command line arguments (*without the name of the executable*)
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args {
+        server: SERVER.to_string(),
+        nick: None,
+        codec: &codec::LNPKG,
+    };
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let value = match flag.as_str() {
+            "--server" | "--nick" | "--codec" => args
+                .next()
+                .ok_or_else(|| format!("The option '{}' requires a value.", flag))?,
+            _ => return Err(format!("Unknown option '{}'.\n{}", flag, USAGE)),
+        };
+        match flag.as_str() {
+            "--server" => parsed.server = value,
+            "--nick" => parsed.nick = Some(value),
+            _ => {
+                parsed.codec = codec::by_name(&value)
+                    .ok_or_else(|| format!("Unknown codec '{}'.\n{}", value, USAGE))?
+            }
+        }
+    }
+    Ok(parsed)
+}
 
 fn main() {
-    let mut server = net::TcpStream::connect(SERVER).unwrap();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut server = net::TcpStream::connect(&args.server).unwrap();
+
+    // The server doesn't do anything until it receives the hello
+    let template: Lnp = ClientMessage::Hello {
+        version: shared::PROTOCOL_VERSION,
+        name: env!("CARGO_PKG_NAME").to_string(),
+        software_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: capability::ALL.iter().map(|c| c.to_string()).collect(),
+        nick: args.nick,
+    }
+    .into();
+    let codec = args.codec;
+    send(&mut server, codec, &template).unwrap();
 
     let server_clone = server.try_clone().unwrap();
-    thread::spawn(move || sender(server_clone));
+    thread::spawn(move || sender(server_clone, codec));
 
     // Reading from the tcp stream in a loop
+    let mut pong_stream = server.try_clone().unwrap();
+    let mut reader = framing::FrameReader::with_framing(server, codec.framing(), MAX_MESSAGE_SIZE);
     loop {
-        let mut buffer = vec![0; BUFF_SIZE];
-
-        if server.read(&mut buffer).unwrap() == 0 {
-            println!("Connection closed.");
-            std::process::exit(1);
-        } else {
-            println!("Message received: {}", String::from_utf8(buffer).unwrap());
+        match reader.read_frame().unwrap() {
+            Some(frame) => {
+                let pkg = match codec.decode(&frame) {
+                    Ok(pkg) => pkg,
+                    Err(e) => {
+                        println!(
+                            "Message received ({}): {}",
+                            e,
+                            String::from_utf8_lossy(&frame)
+                        );
+                        continue;
+                    }
+                };
+                let raw = pkg.to_string();
+                match ServerMessage::try_from(pkg) {
+                    // Pings are answered automatically, without bothering the user
+                    Ok(ServerMessage::Ping) => {
+                        let pong: Lnp = ClientMessage::Pong.into();
+                        if let Err(e) = send(&mut pong_stream, codec, &pong) {
+                            eprintln!("Error answering ping: {:?}", e);
+                        }
+                    }
+                    Ok(msg) => println!("{}", render::render(msg)),
+                    Err(e) => println!("Message received ({}): {}", e, raw),
+                }
+            }
+            None => {
+                println!("Connection closed.");
+                std::process::exit(1);
+            }
         }
     }
 }
 
+/// Writes the package to the stream, framed as the codec requires
+fn send(stream: &mut net::TcpStream, codec: &dyn Codec, pkg: &Lnp) -> io::Result<()> {
+    stream.write_all(&codec.encode_frame(pkg)?)
+}
+
 fn get_input(prompt: &str) -> String {
     print!("{}", prompt);
     io::stdout().flush().unwrap();
@@ -38,7 +132,9 @@ fn get_input(prompt: &str) -> String {
     buffer
 }
 
-fn sender(mut server: net::TcpStream) {
+fn sender(mut server: net::TcpStream, codec: &dyn Codec) {
+    // Id attached to each command, echoed by the server in the result package
+    let mut request_id: i128 = 0;
     loop {
         let message = get_input("SEND ME> ");
 
@@ -46,14 +142,26 @@ fn sender(mut server: net::TcpStream) {
         if message.starts_with(":") {
             let input = syntax::Input::from_string(message[1..].to_string());
             println!("SENDING RAW MESSAGE: {:?}", &input);
-            let template = msg_templates::client::command(input.command, input.arguments);
+            request_id += 1;
+            let template: Lnp = ClientMessage::Command {
+                command: input.command,
+                args: input.arguments,
+                request_id: Some(request_id),
+            }
+            .into();
             println!("SENDING COMMAND: {:?}", &template.to_string());
-            match server.write(template.as_bytes().as_slice()) {
-                Ok(_) => println!("Command sent"),
+            match send(&mut server, codec, &template) {
+                Ok(_) => println!("Command sent (request id {})", request_id),
                 Err(e) => eprintln!("Error sending command: {:?}", e),
             };
         } else {
-            match server.write(msg_templates::client::msg(message).as_bytes().as_slice()) {
+            // Messages starting with `#room ` go to that room, the rest to the lobby
+            let (room, message) = match message.strip_prefix('#').and_then(|m| m.split_once(' ')) {
+                Some((room, message)) => (Some(room.to_string()), message.to_string()),
+                None => (None, message),
+            };
+            let template: Lnp = ClientMessage::Message { msg: message, room }.into();
+            match send(&mut server, codec, &template) {
                 Ok(_) => println!("Messsage sent"),
                 Err(e) => eprintln!("Error occurred: {:?}", e),
             }
@@ -0,0 +1,92 @@
+use msg_templates::messages::ServerMessage;
+use msg_templates::server::ClientSummary;
+use msg_templates::Lnp;
+
+/// Turns a package received from the server into the text shown to the user.
+pub fn render(msg: ServerMessage) -> String {
+    match msg {
+        ServerMessage::DirectMessage { .. }
+        | ServerMessage::NickDirectMessage { .. }
+        | ServerMessage::GroupDirectMessage { .. } => render_direct_message(&msg),
+        ServerMessage::ListClients(clients) => render_client_list(&clients),
+        ServerMessage::Motd(motd) => format!("Message of the day: {}", motd),
+        ServerMessage::Welcome {
+            version,
+            name,
+            software_version,
+            capabilities,
+            codec,
+        } => format!(
+            "Connected to {} {} (protocol version {}, codec {}, capabilities: {})",
+            name,
+            software_version,
+            version,
+            codec,
+            capabilities.join(", ")
+        ),
+        ServerMessage::DeliveryReport { failed, .. } => {
+            if failed.is_empty() {
+                "Direct message delivered.".to_string()
+            } else {
+                let failed: Vec<String> = failed.iter().map(|id| id.to_string()).collect();
+                format!("Direct message not delivered to: {}", failed.join(", "))
+            }
+        }
+        ServerMessage::DeliveryStatus { recipient, status } => {
+            format!("Direct message to {}: {}", recipient, status)
+        }
+        ServerMessage::ClientRenamed { id, old, new } => {
+            format!("{} ({}) is now known as {}", old, id, new)
+        }
+        ServerMessage::Moderation {
+            action,
+            target,
+            operator_id,
+            duration,
+            ..
+        } => {
+            let mut output = format!(
+                "Operator {} used '{}' against {}",
+                operator_id, action, target
+            );
+            if let Some(secs) = duration {
+                output.push_str(&format!(" for {}s", secs));
+            }
+            output
+        }
+        msg => format!("Message received: {}", Lnp::from(msg)),
+    }
+}
+
+/// Renders the direct messages (*`server::direct_message`, and the ones addressed to a
+/// nickname or a group*) along with their author
+pub fn render_direct_message(msg: &ServerMessage) -> String {
+    let (author, msg) = match msg {
+        ServerMessage::DirectMessage {
+            author_id,
+            author_name,
+            msg,
+        }
+        | ServerMessage::NickDirectMessage {
+            author_id,
+            author_name,
+            msg,
+            ..
+        } => (format!("{} ({})", author_name, author_id), msg),
+        ServerMessage::GroupDirectMessage { author_id, msg, .. } => (author_id.to_string(), msg),
+        _ => return String::new(),
+    };
+    format!("Direct message from {}: {}", author, msg)
+}
+
+/// Renders the clients listed in the `server::list_clients` package as a table
+pub fn render_client_list(clients: &[ClientSummary]) -> String {
+    let mut output = format!("Users online ({}):", clients.len());
+    for client in clients {
+        output.push_str(&format!("\n  [{}] {}", client.id, client.name));
+        if let Some(idle) = client.idle {
+            output.push_str(&format!(" (idle {}s)", idle));
+        }
+    }
+    output
+}
@@ -1,4 +1,5 @@
-use crate::syntax;
+use crate::{parse_args, render, syntax};
+use msg_templates::messages::ServerMessage;
 #[test]
 pub fn test_basic_syntax() {
     let sample = "command argument1 \"complex argument\"".to_string();
@@ -49,3 +50,61 @@ pub fn empty() {
     assert_eq!(output, syntax::parse_string_to_segments(sample));
 }
 
+#[test]
+pub fn render_client_list() {
+    let clients = vec![
+        msg_templates::server::ClientSummary {
+            id: 1,
+            name: "first".to_string(),
+            connected_since: Some(0),
+            idle: Some(5),
+        },
+        msg_templates::server::ClientSummary {
+            id: 2,
+            name: "second".to_string(),
+            connected_since: None,
+            idle: None,
+        },
+    ];
+    let output = "Users online (2):\n  [1] first (idle 5s)\n  [2] second";
+    assert_eq!(output, render::render_client_list(&clients));
+}
+
+#[test]
+pub fn args() {
+    let args = parse_args(vec![
+        "--nick".to_string(),
+        "someone".to_string(),
+        "--server".to_string(),
+        "10.0.0.1:9000".to_string(),
+    ])
+    .unwrap();
+    assert_eq!("10.0.0.1:9000", args.server);
+    assert_eq!(Some("someone".to_string()), args.nick);
+    assert_eq!("lnpkg", args.codec.name());
+
+    let args = parse_args(vec!["--codec".to_string(), "json".to_string()]).unwrap();
+    assert_eq!("json", args.codec.name());
+
+    assert!(parse_args(vec!["--nick".to_string()]).is_err());
+    assert!(parse_args(vec!["--unknown".to_string()]).is_err());
+    assert!(parse_args(vec!["--codec".to_string(), "xml".to_string()]).is_err());
+}
+
+#[test]
+pub fn render_direct_message() {
+    let msg = ServerMessage::DirectMessage {
+        author_id: 3,
+        author_name: "someone".to_string(),
+        msg: "hi".to_string(),
+    };
+    assert_eq!(
+        "Direct message from someone (3): hi",
+        render::render_direct_message(&msg)
+    );
+
+    // Through the whole package
+    let pkg = msg_templates::server::group_direct_message(3, vec![4, 5], "hi".to_string());
+    let msg = ServerMessage::try_from(pkg).unwrap();
+    assert_eq!("Direct message from 3: hi", render::render(msg));
+}
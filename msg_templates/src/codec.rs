@@ -0,0 +1,209 @@
+//! Codecs turn the packages into the payload of the frames sent, and back.
+//!
+//! Every connection uses a single codec, picked by the client with the first byte it
+//! sends, ignoring whitespace (*see `detect`*): `{` means that it speaks `JsonCodec`,
+//! anything else `LnPkgCodec`.
+use super::*;
+use crate::framing::{FrameError, Framing};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Errors that can be found while decoding a payload
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    NonValidUtf8,
+    /// The payload isn't valid JSON, or its values can't be carried by a package
+    NonValidJson(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NonValidUtf8 => write!(f, "The payload isn't valid UTF-8."),
+            Self::NonValidJson(reason) => write!(f, "Non valid JSON package: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Format in which the packages are sent
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Name of the codec, sent in the `welcome` package
+    fn name(&self) -> &'static str;
+
+    /// How the frames carrying the payloads are delimited
+    fn framing(&self) -> Framing;
+
+    /// Payload that carries the package
+    fn encode(&self, pkg: &Lnp) -> Vec<u8>;
+
+    fn decode(&self, payload: &[u8]) -> Result<Lnp, CodecError>;
+
+    /// Whole frame (*payload included*) that carries the package
+    fn encode_frame(&self, pkg: &Lnp) -> Result<Vec<u8>, FrameError> {
+        self.framing().encode(&self.encode(pkg))
+    }
+}
+
+/// The `key=value:` syntax of `lnpkg`, in length prefixed frames
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LnPkgCodec;
+
+/// One JSON object per line, with the type of the package in the `type` key and its
+/// content in the rest (*eg. `{"type":"message","msg":"hi","room":"lobby"}`*). Lists
+/// are arrays of strings.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+pub static LNPKG: LnPkgCodec = LnPkgCodec;
+pub static JSON: JsonCodec = JsonCodec;
+
+/// Codec with the name specified
+pub fn by_name(name: &str) -> Option<&'static dyn Codec> {
+    match name {
+        "lnpkg" => Some(&LNPKG),
+        "json" => Some(&JSON),
+        _ => None,
+    }
+}
+
+/// Codec spoken by a peer whose first byte sent is the one specified (*the whitespace
+/// sent before it must be skipped, see `framing::FrameReader::peek_non_whitespace`*)
+pub fn detect(first_byte: u8) -> &'static dyn Codec {
+    if first_byte == b'{' {
+        &JSON
+    } else {
+        &LNPKG
+    }
+}
+
+impl Codec for LnPkgCodec {
+    fn name(&self) -> &'static str {
+        "lnpkg"
+    }
+
+    fn framing(&self) -> Framing {
+        Framing::LengthPrefixed
+    }
+
+    fn encode(&self, pkg: &Lnp) -> Vec<u8> {
+        pkg.as_bytes()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Lnp, CodecError> {
+        let payload = std::str::from_utf8(payload).map_err(|_| CodecError::NonValidUtf8)?;
+        Ok(Lnp::from_string(payload))
+    }
+}
+
+/// Name of the type of package in the `type` key of the JSON packages
+fn type_name(pkg_type: &Lpty) -> &'static str {
+    match pkg_type {
+        Lpty::Message => "message",
+        Lpty::DirectMessage => "direct_message",
+        Lpty::Command => "command",
+        Lpty::SelfIdentity => "self_identity",
+        Lpty::Identity => "identity",
+        Lpty::EventClientConnected => "client_connected",
+        Lpty::EventClientLeft => "client_left",
+        _ => "unknown",
+    }
+}
+
+/// Opposite of `type_name`
+fn type_from_name(name: &str) -> Lpty {
+    match name {
+        "message" => Lpty::Message,
+        "direct_message" => Lpty::DirectMessage,
+        "command" => Lpty::Command,
+        "self_identity" => Lpty::SelfIdentity,
+        "identity" => Lpty::Identity,
+        "client_connected" => Lpty::EventClientConnected,
+        "client_left" => Lpty::EventClientLeft,
+        _ => Lpty::Unknown,
+    }
+}
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn framing(&self) -> Framing {
+        Framing::Lines
+    }
+
+    fn encode(&self, pkg: &Lnp) -> Vec<u8> {
+        let mut object = Map::new();
+        object.insert("type".to_string(), Value::from(type_name(&pkg.pkg_type)));
+        for (key, value) in pkg.content.iter() {
+            let value = match value {
+                // Integers that don't fit in a JSON number are sent as text
+                Lpv::Int(i) => match i64::try_from(*i) {
+                    Ok(i) => Value::from(i),
+                    Err(_) => Value::from(i.to_string()),
+                },
+                Lpv::String(s) => Value::from(s.as_str()),
+                Lpv::List(l) => Value::from(l.clone()),
+                Lpv::Null => Value::Null,
+            };
+            object.insert(key.clone(), value);
+        }
+        // Serializing a map of strings and plain values can't fail
+        serde_json::to_vec(&Value::Object(object)).unwrap_or_default()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Lnp, CodecError> {
+        let object = match serde_json::from_slice(payload) {
+            Ok(Value::Object(object)) => object,
+            Ok(_) => return Err(CodecError::NonValidJson("not an object".to_string())),
+            Err(e) => return Err(CodecError::NonValidJson(e.to_string())),
+        };
+
+        let mut pkg_type = Lpty::Unknown;
+        let mut content = HashMap::new();
+        for (key, value) in object {
+            if key == "type" {
+                if let Value::String(name) = &value {
+                    pkg_type = type_from_name(name);
+                }
+                continue;
+            }
+            let value = match value {
+                Value::Null => Lpv::Null,
+                Value::String(s) => Lpv::String(s),
+                Value::Number(n) => match n.as_i64() {
+                    Some(i) => Lpv::Int(i as i128),
+                    None => {
+                        return Err(CodecError::NonValidJson(format!(
+                            "'{}' isn't an integer",
+                            key
+                        )))
+                    }
+                },
+                Value::Array(elements) => Lpv::List(
+                    elements
+                        .into_iter()
+                        .map(|element| match element {
+                            Value::String(s) => Ok(s),
+                            Value::Number(n) => Ok(n.to_string()),
+                            _ => Err(CodecError::NonValidJson(format!(
+                                "'{}' should only contain strings or numbers",
+                                key
+                            ))),
+                        })
+                        .collect::<Result<_, _>>()?,
+                ),
+                Value::Bool(_) | Value::Object(_) => {
+                    return Err(CodecError::NonValidJson(format!(
+                        "'{}' has a type of value not supported",
+                        key
+                    )))
+                }
+            };
+            content.insert(key, value);
+        }
+        Ok(Lnp::from_hashmap(content, pkg_type))
+    }
+}
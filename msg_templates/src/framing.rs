@@ -0,0 +1,281 @@
+//! Framing layer used by both the server and the clients.
+//!
+//! A single `read()` on a `TcpStream` doesn't map to a single package: TCP might
+//! merge two packages into one segment, or split a long package across several.
+//! To keep the boundaries of each package, every package is sent as a **frame**,
+//! which consists of a 4 byte big-endian length header followed by the payload.
+//!
+//! ```text
+//! +----------------+---------------------+
+//! | len (u32, BE)  | payload (len bytes) |
+//! +----------------+---------------------+
+//! ```
+//!
+//! Codecs whose payloads never contain newlines (*see `codec::JsonCodec`*) use
+//! `Framing::Lines` instead, where every payload is followed by a `\n`.
+use std::io::{self, Read, Write};
+
+/// Size of the length header that precedes every frame.
+pub const HEADER_SIZE: usize = 4;
+/// Default maximum size for the payload of a frame (*1 MiB*).
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+/// Errors that can be found while decoding frames
+pub enum FrameError {
+    /// The header of the frame announced a payload bigger than the maximum allowed, or
+    /// the payload to send doesn't fit in the header (*see `encode_header`*).
+    TooLarge { size: usize, max: usize },
+}
+
+/// How the frames are delimited in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Length header followed by the payload
+    LengthPrefixed,
+    /// Payload followed by a newline (*empty lines are skipped*)
+    Lines,
+}
+
+impl Framing {
+    /// Wraps the payload given in a frame, returning the bytes to be sent.
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+        match self {
+            Self::LengthPrefixed => encode_frame(payload),
+            Self::Lines => {
+                let mut frame = Vec::with_capacity(payload.len() + 1);
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+                Ok(frame)
+            }
+        }
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+    }
+}
+
+/// Header of a frame whose payload has the size specified, or an error if the size
+/// doesn't fit in it (*payloads of more than 4 GiB*).
+pub fn encode_header(size: usize) -> Result<[u8; HEADER_SIZE], FrameError> {
+    match u32::try_from(size) {
+        Ok(size) => Ok(size.to_be_bytes()),
+        Err(_) => Err(FrameError::TooLarge {
+            size,
+            max: u32::MAX as usize,
+        }),
+    }
+}
+
+/// Wraps the payload given in a frame, returning the bytes to be sent.
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let header = encode_header(payload.len())?;
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Writes the payload as a frame in the writer specified.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(payload)?)?;
+    writer.flush()
+}
+
+/// Streaming decoder, bytes are pushed as they arrive (*in chunks of any size*), and
+/// complete frames are taken out of it once all of their bytes have been received.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    framing: Framing,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self::with_framing(Framing::LengthPrefixed, max_frame_size)
+    }
+
+    pub fn with_framing(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size,
+            framing,
+        }
+    }
+
+    /// Changes the framing of the next frames (*the bytes already buffered are kept*)
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// First byte buffered, if any
+    pub fn first_byte(&self) -> Option<u8> {
+        self.buffer.first().copied()
+    }
+
+    /// Discards the ASCII whitespace at the start of the bytes buffered
+    pub fn skip_whitespace(&mut self) {
+        let start = self
+            .buffer
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(self.buffer.len());
+        self.buffer.drain(..start);
+    }
+
+    /// Appends the bytes received to the internal buffer.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the payload of the next complete frame, `Ok(None)` if there are not enough
+    /// bytes buffered yet, or an error if the frame announced is bigger than the maximum.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.framing == Framing::Lines {
+            return self.next_line();
+        }
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..HEADER_SIZE]);
+        let size = u32::from_be_bytes(header) as usize;
+
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+
+        if self.buffer.len() < HEADER_SIZE + size {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+        self.buffer.drain(..HEADER_SIZE + size);
+        Ok(Some(payload))
+    }
+
+    /// Same as `next_frame`, for `Framing::Lines`
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            let end = match self.buffer.iter().position(|b| *b == b'\n') {
+                Some(end) => end,
+                None if self.buffer.len() > self.max_frame_size => {
+                    return Err(FrameError::TooLarge {
+                        size: self.buffer.len(),
+                        max: self.max_frame_size,
+                    })
+                }
+                None => return Ok(None),
+            };
+            if end > self.max_frame_size {
+                return Err(FrameError::TooLarge {
+                    size: end,
+                    max: self.max_frame_size,
+                });
+            }
+
+            let mut payload: Vec<u8> = self.buffer.drain(..=end).collect();
+            payload.pop();
+            if payload.last() == Some(&b'\r') {
+                payload.pop();
+            }
+            if !payload.is_empty() {
+                return Ok(Some(payload));
+            }
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+/// Reads frames from a reader (*such as a `TcpStream`*) using a `FrameDecoder`.
+pub struct FrameReader<R: Read> {
+    reader: R,
+    decoder: FrameDecoder,
+    chunk: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R, max_frame_size: usize) -> Self {
+        Self::with_framing(reader, Framing::LengthPrefixed, max_frame_size)
+    }
+
+    pub fn with_framing(reader: R, framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            reader,
+            decoder: FrameDecoder::with_framing(framing, max_frame_size),
+            chunk: vec![0; 1024],
+        }
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.decoder.set_framing(framing);
+    }
+
+    /// Blocks until the first byte of the next frame arrives, returning it without
+    /// consuming it (*`Ok(None)` if the connection has been closed*)
+    pub fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            if let Some(byte) = self.decoder.first_byte() {
+                return Ok(Some(byte));
+            }
+
+            let read = self.reader.read(&mut self.chunk)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.decoder.push(&self.chunk[..read]);
+        }
+    }
+
+    /// Same as `peek_byte`, but the ASCII whitespace that arrives before the byte is
+    /// discarded. Only meant for the start of a connection, since the headers of the
+    /// length prefixed frames might start with what looks like whitespace (*although
+    /// only for frames of 150MB or more*).
+    pub fn peek_non_whitespace(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            self.decoder.skip_whitespace();
+            if let Some(byte) = self.decoder.first_byte() {
+                return Ok(Some(byte));
+            }
+
+            let read = self.reader.read(&mut self.chunk)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.decoder.push(&self.chunk[..read]);
+        }
+    }
+
+    /// Blocks until a whole frame has been read, returning its payload, or `Ok(None)`
+    /// if the connection has been closed.
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+
+            let read = self.reader.read(&mut self.chunk)?;
+            if read == 0 {
+                // Empty packet (Connection closed)
+                return Ok(None);
+            }
+            self.decoder.push(&self.chunk[..read]);
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+}
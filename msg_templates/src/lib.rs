@@ -4,6 +4,51 @@ pub type Lpv = lnpkg::LnPkgValue; // LakenetPackageValue
 pub type Lpty = lnpkg::LnPkgType; // LakeNetPackageType
 pub type Lnp = lnpkg::LnPkg; // LakeNetPackage
 
+pub mod codec;
+pub mod framing;
+pub mod messages;
+
+/// Room every client joins when connecting, and the one messages without a room go to
+pub const LOBBY: &str = "lobby";
+
+/// Packages that don't have a `LnPkgType` of their own are sent as `Command` packages,
+/// where the `command` key identifies the kind of package. This function returns the
+/// value of that key (*`None` if the package is not a `Command`*).
+pub fn command_name(pkg: &Lnp) -> Option<&str> {
+    if pkg.pkg_type != Lpty::Command {
+        return None;
+    }
+    match pkg.content.get("command") {
+        Some(Lpv::String(command)) => Some(command.as_str()),
+        _ => None,
+    }
+}
+
+/// Stable codes sent in the `code` key of the `server::error` package, one for
+/// each of the errors that the server can report back to the client.
+pub mod error_code {
+    pub const NON_VALID_FORMAT: i128 = 1;
+    pub const NO_MESSAGE_TYPE: i128 = 2;
+    pub const UNKNOWN_MESSAGE_TYPE: i128 = 3;
+    pub const UNKNOWN_USER: i128 = 4;
+    pub const RESOURCE_NOT_AVAILABLE: i128 = 5;
+    pub const UNKNOWN_COMMAND: i128 = 6;
+    pub const NON_VALID_COMMAND_USAGE: i128 = 7;
+    pub const INTERNAL_SERVER_ERROR: i128 = 8;
+    pub const USERNAME_TAKEN: i128 = 9;
+    pub const INVALID_CREDENTIALS: i128 = 10;
+    pub const SERVER_FULL: i128 = 11;
+    pub const PERMISSION_DENIED: i128 = 12;
+    pub const MUTED: i128 = 13;
+    pub const BANNED: i128 = 14;
+    pub const NOT_LOGGED_IN: i128 = 15;
+    pub const INVALID_NICKNAME: i128 = 16;
+    pub const RESERVED_NICKNAME: i128 = 17;
+    pub const NICKNAME_TAKEN: i128 = 18;
+    pub const HANDSHAKE_REQUIRED: i128 = 19;
+    pub const INCOMPATIBLE_VERSION: i128 = 20;
+}
+
 /// Message templates used by the client
 pub mod client {
     use super::*;
@@ -16,19 +61,23 @@ pub mod client {
         Lnp::new(Lpty::SelfIdentity)
     }
 
-    /// Request for the identity of the client associated with the ID specified.
-    pub fn id_request(client_id: lnpkg::ClientId, client_name: String) -> Lnp {
+    /// Request for the identity of the client associated with the ID specified, the
+    /// server answers with a `server::identity` package.
+    pub fn id_request(client_id: lnpkg::ClientId) -> Lnp {
         let mut hm = HashMap::new();
         hm.insert("id".to_string(), Lpv::Int(client_id));
-        hm.insert("name".to_string(), Lpv::String(client_name));
         Lnp::from_hashmap(hm, Lpty::Identity)
     }
 
     /// Message **sent by the client** to the server, with the purpose of
-    /// being broadcasted to the rest of clients
-    pub fn msg(msg: String) -> Lnp {
+    /// being broadcasted to the rest of clients in the room specified (*`LOBBY`
+    /// if no room is specified*)
+    pub fn msg(msg: String, room: Option<String>) -> Lnp {
         let mut hm = HashMap::new();
         hm.insert("msg".to_string(), Lpv::String(msg));
+        if let Some(room) = room {
+            hm.insert("room".to_string(), Lpv::String(room));
+        }
         lnpkg::LnPkg::from_hashmap(hm, Lpty::Message)
     }
 
@@ -41,27 +90,77 @@ pub mod client {
         lnpkg::LnPkg::from_hashmap(hm, Lpty::DirectMessage)
     }
 
+    /// Same as `direct_message`, but addressed to the owner of a nickname. If the owner
+    /// isn't connected, the server keeps the message until they take the nickname again
+    /// (*as long as the nickname has been claimed, see the `chnick` command*). The `kind`
+    /// key marks it as a nickname, since a nickname made of digits can't be told apart
+    /// from an id once sent.
+    pub fn nick_direct_message(nick: String, msg: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("id".to_string(), Lpv::String(nick));
+        hm.insert("kind".to_string(), Lpv::String("nick".to_string()));
+        hm.insert("msg".to_string(), Lpv::String(msg));
+        lnpkg::LnPkg::from_hashmap(hm, Lpty::DirectMessage)
+    }
+
+    /// Same as `direct_message`, but sent to several clients at once. Every recipient
+    /// gets the list of all of them. The `kind` key marks it as a group, since a list of
+    /// a single id can't be told apart from the id once sent.
+    pub fn group_direct_message(client_ids: Vec<lnpkg::ClientId>, msg: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert(
+            "id".to_string(),
+            Lpv::List(client_ids.iter().map(|id| id.to_string()).collect()),
+        );
+        hm.insert("kind".to_string(), Lpv::String("group".to_string()));
+        hm.insert("msg".to_string(), Lpv::String(msg));
+        lnpkg::LnPkg::from_hashmap(hm, Lpty::DirectMessage)
+    }
+
     /// Message **sent by the client**, requesting the **server** to do an operation
-    /// which can result in success or error
-    pub fn command(command: String, arguments: Vec<String>) -> Lnp {
+    /// which can result in success or error. If a `request_id` is given, the server
+    /// will echo it in the `server::command_result` package sent back.
+    pub fn command(command: String, arguments: Vec<String>, request_id: Option<i128>) -> Lnp {
         let mut hm = HashMap::new();
         hm.insert("command".to_string(), Lpv::String(command));
         hm.insert("args".to_string(), Lpv::List(arguments));
+        if let Some(request_id) = request_id {
+            hm.insert("request_id".to_string(), Lpv::Int(request_id));
+        }
 
         lnpkg::LnPkg::from_hashmap(hm, Lpty::Command)
     }
+
+    /// Answer to the `server::ping` package, proving that the client is still alive.
+    pub fn pong() -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("pong".to_string()));
+        hm.insert("args".to_string(), Lpv::List(vec![]));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
 }
 
 /// Message templates used by the server
 pub mod server {
     use super::*;
     /// Message **sent by client**, broadcasted by the server to the
-    /// rest of clients connected. <br>*Side note: The `msg` parameter only refers to
+    /// rest of clients in the room. <br>*Side note: The `msg` parameter only refers to
     /// the string that the client wants the other clients to see, not the `lnpkg` string.*
-    pub fn msg(client_id: lnpkg::ClientId, msg: String) -> Lnp {
+    /// <br>`msg_id` identifies the message in the history of the server (*see the `history`
+    /// command*), and `timestamp` is the moment it was sent (*in seconds since the UNIX epoch*).
+    pub fn msg(
+        client_id: lnpkg::ClientId,
+        msg: String,
+        room: String,
+        msg_id: u64,
+        timestamp: u64,
+    ) -> Lnp {
         let mut hm = HashMap::new();
         hm.insert("client".to_string(), Lpv::Int(client_id as i128));
         hm.insert("msg".to_string(), Lpv::String(msg));
+        hm.insert("room".to_string(), Lpv::String(room));
+        hm.insert("msg_id".to_string(), Lpv::Int(msg_id as i128));
+        hm.insert("timestamp".to_string(), Lpv::Int(timestamp as i128));
         Lnp::from_hashmap(hm, Lpty::Message)
     }
 
@@ -73,6 +172,86 @@ pub mod server {
         Lnp::from_hashmap(hm, Lpty::SelfIdentity)
     }
 
+    /// Direct message sent to a group of clients, `recipients` contains the ids of
+    /// every client in the group.
+    pub fn group_direct_message(
+        author_id: lnpkg::ClientId,
+        recipients: Vec<lnpkg::ClientId>,
+        msg: String,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("client".to_string(), Lpv::Int(author_id));
+        hm.insert(
+            "recipients".to_string(),
+            Lpv::List(recipients.iter().map(|id| id.to_string()).collect()),
+        );
+        hm.insert("msg".to_string(), Lpv::String(msg));
+        Lnp::from_hashmap(hm, Lpty::DirectMessage)
+    }
+
+    /// Direct message sent by another client, only to the client receiving it
+    pub fn direct_message(author_id: lnpkg::ClientId, author_name: String, msg: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("client".to_string(), Lpv::Int(author_id));
+        hm.insert("name".to_string(), Lpv::String(author_name));
+        hm.insert("msg".to_string(), Lpv::String(msg));
+        Lnp::from_hashmap(hm, Lpty::DirectMessage)
+    }
+
+    /// Direct message addressed to the nickname of the client, `timestamp` is the moment
+    /// in which it was sent (*in seconds since the UNIX epoch*), which might be long ago if
+    /// the client wasn't connected.
+    pub fn nick_direct_message(
+        author_id: lnpkg::ClientId,
+        author_name: String,
+        nick: String,
+        msg: String,
+        timestamp: u64,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("client".to_string(), Lpv::Int(author_id));
+        hm.insert("name".to_string(), Lpv::String(author_name));
+        hm.insert("to".to_string(), Lpv::String(nick));
+        hm.insert("msg".to_string(), Lpv::String(msg));
+        hm.insert("timestamp".to_string(), Lpv::Int(timestamp as i128));
+        Lnp::from_hashmap(hm, Lpty::DirectMessage)
+    }
+
+    /// Sent to the author of a direct message, `status` is `delivered` once the message
+    /// has been written to the connection of the recipient, or `queued` if it will get it
+    /// the next time it connects. `recipient` is the id or nickname the message was sent to.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `delivery_status`.*
+    pub fn delivery_status(recipient: String, status: &str) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert(
+            "command".to_string(),
+            Lpv::String("delivery_status".to_string()),
+        );
+        hm.insert("recipient".to_string(), Lpv::String(recipient));
+        hm.insert("status".to_string(), Lpv::String(status.to_string()));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Sent to the author of a direct message, with the recipients that got the message
+    /// and the ones that didn't (*eg. because they aren't connected*).
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `delivery_report`.*
+    pub fn delivery_report(delivered: Vec<lnpkg::ClientId>, failed: Vec<lnpkg::ClientId>) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert(
+            "command".to_string(),
+            Lpv::String("delivery_report".to_string()),
+        );
+        hm.insert(
+            "delivered".to_string(),
+            Lpv::List(delivered.iter().map(|id| id.to_string()).collect()),
+        );
+        hm.insert(
+            "failed".to_string(),
+            Lpv::List(failed.iter().map(|id| id.to_string()).collect()),
+        );
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
     /// Contains the identity of a client specified
     pub fn identity(client_id: lnpkg::ClientId, client_name: String) -> Lnp {
         let mut hm = HashMap::new();
@@ -81,9 +260,50 @@ pub mod server {
         Lnp::from_hashmap(hm, Lpty::Identity)
     }
 
-    /// Message that contains a list of all the clients
-    pub fn list_clients(_: HashMap<lnpkg::ClientId, String>) -> Lnp {
-        todo!("Create format for the list_clients package")
+    /// Information of a connected client, listed in the `list_clients` package
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ClientSummary {
+        pub id: lnpkg::ClientId,
+        pub name: String,
+        /// Unix timestamp (*in seconds*) of the moment the client connected
+        pub connected_since: Option<u64>,
+        /// Seconds since the last package sent by the client
+        pub idle: Option<u64>,
+    }
+
+    /// Message that contains a list of all the clients, sent as parallel lists where
+    /// the element `n` of each list belongs to the same client. Optional values that
+    /// aren't known are sent as an empty string.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `list_clients`.*
+    pub fn list_clients(clients: Vec<ClientSummary>) -> Lnp {
+        let optional = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+        let mut hm = HashMap::new();
+        hm.insert(
+            "command".to_string(),
+            Lpv::String("list_clients".to_string()),
+        );
+        hm.insert(
+            "ids".to_string(),
+            Lpv::List(clients.iter().map(|c| c.id.to_string()).collect()),
+        );
+        hm.insert(
+            "names".to_string(),
+            Lpv::List(clients.iter().map(|c| c.name.clone()).collect()),
+        );
+        hm.insert(
+            "connected_since".to_string(),
+            Lpv::List(
+                clients
+                    .iter()
+                    .map(|c| optional(c.connected_since))
+                    .collect(),
+            ),
+        );
+        hm.insert(
+            "idle".to_string(),
+            Lpv::List(clients.iter().map(|c| optional(c.idle)).collect()),
+        );
+        Lnp::from_hashmap(hm, Lpty::Command)
     }
 
     pub fn event_client_connected(client_id: lnpkg::ClientId, client_name: String) -> Lnp {
@@ -99,10 +319,232 @@ pub mod server {
         hm.insert("name".to_string(), Lpv::String(client_name));
         Lnp::from_hashmap(hm, Lpty::EventClientLeft)
     }
+
+    /// Sent to every client when a client changes its nickname.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `client_renamed`.*
+    pub fn event_client_renamed(client_id: lnpkg::ClientId, old: String, new: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert(
+            "command".to_string(),
+            Lpv::String("client_renamed".to_string()),
+        );
+        hm.insert("id".to_string(), Lpv::Int(client_id));
+        hm.insert("old".to_string(), Lpv::String(old));
+        hm.insert("new".to_string(), Lpv::String(new));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Sent to the members of a room (*including the new one*) when a client joins it.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `room_joined`.*
+    pub fn event_room_joined(room: String, client_id: lnpkg::ClientId, client_name: String) -> Lnp {
+        room_event("room_joined", room, client_id, client_name)
+    }
+
+    /// Sent to the members of a room (*including the one leaving*) when a client parts it.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `room_parted`.*
+    pub fn event_room_parted(room: String, client_id: lnpkg::ClientId, client_name: String) -> Lnp {
+        room_event("room_parted", room, client_id, client_name)
+    }
+
+    fn room_event(
+        command: &str,
+        room: String,
+        client_id: lnpkg::ClientId,
+        client_name: String,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String(command.to_string()));
+        hm.insert("room".to_string(), Lpv::String(room));
+        hm.insert("id".to_string(), Lpv::Int(client_id));
+        hm.insert("name".to_string(), Lpv::String(client_name));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Action taken by an operator (`kick`, `ban`, `unban`, `mute` or `unmute`) against the
+    /// client specified (*or the address specified, in which case `client_id` is `None`*).
+    /// `operator_id` is the id of the operator that took the action, and `duration` the
+    /// amount of seconds it lasts (*`None` if it lasts until it's undone*).
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `moderation`.*
+    pub fn event_moderation(
+        action: &str,
+        client_id: Option<lnpkg::ClientId>,
+        target: String,
+        operator_id: lnpkg::ClientId,
+        duration: Option<u64>,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("moderation".to_string()));
+        hm.insert("action".to_string(), Lpv::String(action.to_string()));
+        hm.insert(
+            "id".to_string(),
+            client_id.map(Lpv::Int).unwrap_or(Lpv::Null),
+        );
+        hm.insert("target".to_string(), Lpv::String(target));
+        hm.insert("by".to_string(), Lpv::Int(operator_id));
+        hm.insert(
+            "duration".to_string(),
+            duration.map(|d| Lpv::Int(d as i128)).unwrap_or(Lpv::Null),
+        );
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// List of the rooms that have members, sent as parallel lists of their names and
+    /// amount of members.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `list_rooms`.*
+    pub fn list_rooms(rooms: Vec<(String, usize)>) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("list_rooms".to_string()));
+        hm.insert(
+            "names".to_string(),
+            Lpv::List(rooms.iter().map(|(name, _)| name.clone()).collect()),
+        );
+        hm.insert(
+            "members".to_string(),
+            Lpv::List(
+                rooms
+                    .iter()
+                    .map(|(_, members)| members.to_string())
+                    .collect(),
+            ),
+        );
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Message of the day, sent to the clients right after the `identity` package.
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `motd`.*
+    pub fn motd(motd: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("motd".to_string()));
+        hm.insert("msg".to_string(), Lpv::String(motd));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Sent to a client that hasn't sent anything in a while, it must answer with a
+    /// `client::pong` package or it will be disconnected.
+    pub fn ping() -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("ping".to_string()));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Sent to the client when its input couldn't be handled. The `code` is one of the
+    /// constants of `error_code`, and `reason` a human readable explanation of the error.
+    /// <br>*Side note: `lnpkg` doesn't have a type for errors, so it's sent as a
+    /// `Command` package whose `command` key is `error`.*
+    pub fn error(code: i128, reason: String) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("error".to_string()));
+        hm.insert("code".to_string(), Lpv::Int(code));
+        hm.insert("reason".to_string(), Lpv::String(reason));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Outcome of a command sent by the client, `code` is `0` on success, or one
+    /// of the constants of `error_code` otherwise. The `request_id` is the one sent by
+    /// the client in its `client::command` package (*`Null` if it didn't send one*).
+    /// <br>Payloads that are lists are marked with the `kind` key, since a list of a
+    /// single element can't be told apart from the element once sent.
+    pub fn command_result(request_id: Option<i128>, result: Result<Lpv, i128>) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("result".to_string()));
+        hm.insert(
+            "request_id".to_string(),
+            request_id.map(Lpv::Int).unwrap_or(Lpv::Null),
+        );
+        match result {
+            Ok(payload) => {
+                hm.insert("status".to_string(), Lpv::String("ok".to_string()));
+                hm.insert("code".to_string(), Lpv::Int(0));
+                if let Lpv::List(_) = payload {
+                    hm.insert("kind".to_string(), Lpv::String("list".to_string()));
+                }
+                hm.insert("payload".to_string(), payload);
+            }
+            Err(code) => {
+                hm.insert("status".to_string(), Lpv::String("err".to_string()));
+                hm.insert("code".to_string(), Lpv::Int(code));
+                hm.insert("payload".to_string(), Lpv::Null);
+            }
+        }
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
 }
 
 /// Message templates used by both the server and client
 pub mod shared {
-    #[allow(unused_imports)]
     use super::*;
+
+    /// Version of the protocol spoken, sent in the `hello` and `welcome` packages
+    pub const PROTOCOL_VERSION: i128 = 1;
+    /// Oldest version of the protocol that is still understood
+    pub const MIN_PROTOCOL_VERSION: i128 = 1;
+
+    /// Optional features, only used with the clients that list them in their `hello`
+    pub mod capability {
+        /// The last messages of the history are sent when connecting
+        pub const HISTORY: &str = "history";
+        /// The author of a direct message gets a `delivery_status` package
+        pub const RECEIPTS: &str = "receipts";
+
+        /// Every capability known
+        pub const ALL: [&str; 2] = [HISTORY, RECEIPTS];
+    }
+
+    /// Whether somebody speaking the version of the protocol specified can be understood
+    pub fn is_compatible(version: i128) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+    }
+
+    /// First package sent by the client once connected, the server doesn't do anything
+    /// else with the connection until it arrives. `nick` is the nickname the client would
+    /// like to use, the server picks one for it if it's not given (*or not available*).
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `hello`.*
+    pub fn hello(
+        client_name: String,
+        client_version: String,
+        capabilities: Vec<String>,
+        nick: Option<String>,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("hello".to_string()));
+        hm.insert("args".to_string(), Lpv::List(vec![]));
+        hm.insert("version".to_string(), Lpv::Int(PROTOCOL_VERSION));
+        hm.insert("name".to_string(), Lpv::String(client_name));
+        hm.insert("software_version".to_string(), Lpv::String(client_version));
+        hm.insert("capabilities".to_string(), Lpv::List(capabilities));
+        if let Some(nick) = nick {
+            hm.insert("nick".to_string(), Lpv::String(nick));
+        }
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Answer to the `hello` package, sent by the server before the `identity` package.
+    /// `capabilities` are the ones that both sides support, the only ones used from then on,
+    /// and `codec` the name of the codec of the connection (*see `codec::Codec::name`*).
+    /// <br>*Side note: Sent as a `Command` package whose `command` key is `welcome`.*
+    pub fn welcome(
+        server_name: String,
+        server_version: String,
+        capabilities: Vec<String>,
+        codec: String,
+    ) -> Lnp {
+        let mut hm = HashMap::new();
+        hm.insert("command".to_string(), Lpv::String("welcome".to_string()));
+        hm.insert("version".to_string(), Lpv::Int(PROTOCOL_VERSION));
+        hm.insert("name".to_string(), Lpv::String(server_name));
+        hm.insert("software_version".to_string(), Lpv::String(server_version));
+        hm.insert("capabilities".to_string(), Lpv::List(capabilities));
+        hm.insert("codec".to_string(), Lpv::String(codec));
+        Lnp::from_hashmap(hm, Lpty::Command)
+    }
+
+    /// Capabilities listed in a `hello` or `welcome` package
+    pub fn capabilities(pkg: &Lnp) -> Vec<String> {
+        match pkg.content.get("capabilities") {
+            Some(Lpv::List(capabilities)) => capabilities.clone(),
+            // Single element lists might be parsed as a plain value
+            Some(Lpv::String(capability)) if !capability.is_empty() => vec![capability.clone()],
+            _ => vec![],
+        }
+    }
 }
@@ -0,0 +1,610 @@
+//! Typed forms of the packages sent by the clients (*`ClientMessage`*) and by the server
+//! (*`ServerMessage`*). Both convert from a `LnPkg` (*checking that every key needed is
+//! there, with the right type of value*) and into one (*built with the templates of the
+//! `client`, `server` and `shared` modules*).
+use super::*;
+use crate::server::ClientSummary;
+use std::fmt;
+
+/// Reasons why a package couldn't be turned into a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The package doesn't have a type
+    NoType,
+    /// The package has a type that can't be sent in this direction
+    UnexpectedType,
+    /// `Command` package whose `command` key names an unknown kind of package
+    UnknownCommand(String),
+    MissingKey(&'static str),
+    /// The value of the key isn't of the type expected
+    WrongType {
+        key: &'static str,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoType => write!(f, "The package doesn't have a type."),
+            Self::UnexpectedType => write!(f, "The type of the package isn't expected."),
+            Self::UnknownCommand(command) => write!(f, "Unknown kind of package '{}'.", command),
+            Self::MissingKey(key) => write!(f, "The key '{}' is missing.", key),
+            Self::WrongType { key, expected } => {
+                write!(f, "The value of the key '{}' should be {}.", key, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// Destination of a direct message sent by a client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipient {
+    /// The client with the id specified (*see `client::direct_message`*)
+    Client(lnpkg::ClientId),
+    /// Several clients at once (*see `client::group_direct_message`*)
+    Group(Vec<lnpkg::ClientId>),
+    /// The owner of the nickname (*see `client::nick_direct_message`*)
+    Nick(String),
+}
+
+/// Package sent by a client to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// `client::msg`
+    Message { msg: String, room: Option<String> },
+    /// `client::direct_message` and its variants
+    DirectMessage { to: Recipient, msg: String },
+    /// `client::command`
+    Command {
+        command: String,
+        args: Vec<String>,
+        request_id: Option<i128>,
+    },
+    /// `client::pong`
+    Pong,
+    /// `shared::hello`
+    Hello {
+        version: i128,
+        name: String,
+        software_version: String,
+        capabilities: Vec<String>,
+        nick: Option<String>,
+    },
+    /// `client::selfid_request`
+    SelfIdentityRequest,
+    /// `client::id_request`
+    IdentityRequest { id: lnpkg::ClientId },
+}
+
+impl TryFrom<Lnp> for ClientMessage {
+    type Error = ParseError;
+
+    fn try_from(pkg: Lnp) -> ParseResult<Self> {
+        match pkg.pkg_type {
+            Lpty::Message => Ok(Self::Message {
+                msg: text(&pkg, "msg")?,
+                room: optional_text(&pkg, "room")?,
+            }),
+            Lpty::DirectMessage => {
+                let kind = optional_text(&pkg, "kind")?;
+                let to = match pkg.content.get("id") {
+                    // Groups of a single client and nicknames made of digits are told
+                    // apart by their `kind`
+                    _ if kind.as_deref() == Some("group") => Recipient::Group(ids(&pkg, "id")?),
+                    _ if kind.as_deref() == Some("nick") => Recipient::Nick(text(&pkg, "id")?),
+                    Some(Lpv::Int(id)) => Recipient::Client(*id),
+                    Some(Lpv::List(_)) => Recipient::Group(ids(&pkg, "id")?),
+                    Some(Lpv::String(nick)) => Recipient::Nick(nick.clone()),
+                    Some(Lpv::Null) => {
+                        return Err(ParseError::WrongType {
+                            key: "id",
+                            expected: "a client id, a list of them or a nickname",
+                        })
+                    }
+                    None => return Err(ParseError::MissingKey("id")),
+                };
+                Ok(Self::DirectMessage {
+                    to,
+                    msg: text(&pkg, "msg")?,
+                })
+            }
+            Lpty::Command => match text(&pkg, "command")?.as_str() {
+                "pong" => Ok(Self::Pong),
+                "hello" => Ok(Self::Hello {
+                    version: int(&pkg, "version")?,
+                    name: text(&pkg, "name")?,
+                    software_version: text(&pkg, "software_version")?,
+                    capabilities: shared::capabilities(&pkg),
+                    nick: optional_text(&pkg, "nick")?,
+                }),
+                command => Ok(Self::Command {
+                    command: command.to_string(),
+                    args: list(&pkg, "args")?,
+                    request_id: optional_int(&pkg, "request_id")?,
+                }),
+            },
+            Lpty::SelfIdentity => Ok(Self::SelfIdentityRequest),
+            Lpty::Identity => Ok(Self::IdentityRequest {
+                id: int(&pkg, "id")?,
+            }),
+            Lpty::Unknown => Err(ParseError::NoType),
+            _ => Err(ParseError::UnexpectedType),
+        }
+    }
+}
+
+impl From<ClientMessage> for Lnp {
+    fn from(msg: ClientMessage) -> Self {
+        match msg {
+            ClientMessage::Message { msg, room } => client::msg(msg, room),
+            ClientMessage::DirectMessage { to, msg } => match to {
+                Recipient::Client(id) => client::direct_message(id, msg),
+                Recipient::Group(ids) => client::group_direct_message(ids, msg),
+                Recipient::Nick(nick) => client::nick_direct_message(nick, msg),
+            },
+            ClientMessage::Command {
+                command,
+                args,
+                request_id,
+            } => client::command(command, args, request_id),
+            ClientMessage::Pong => client::pong(),
+            ClientMessage::Hello {
+                version,
+                name,
+                software_version,
+                capabilities,
+                nick,
+            } => {
+                let mut pkg = shared::hello(name, software_version, capabilities, nick);
+                pkg.content.insert("version".to_string(), Lpv::Int(version));
+                pkg
+            }
+            ClientMessage::SelfIdentityRequest => Lnp::new(Lpty::SelfIdentity),
+            ClientMessage::IdentityRequest { id } => client::id_request(id),
+        }
+    }
+}
+
+/// Package sent by the server to a client
+#[derive(Debug, PartialEq)]
+pub enum ServerMessage {
+    /// `server::msg`
+    Message {
+        client_id: lnpkg::ClientId,
+        msg: String,
+        room: String,
+        msg_id: u64,
+        timestamp: u64,
+    },
+    /// `server::self_identity`
+    SelfIdentity { id: lnpkg::ClientId, name: String },
+    /// `server::identity`
+    Identity { id: lnpkg::ClientId, name: String },
+    /// `server::direct_message`
+    DirectMessage {
+        author_id: lnpkg::ClientId,
+        author_name: String,
+        msg: String,
+    },
+    /// `server::nick_direct_message`
+    NickDirectMessage {
+        author_id: lnpkg::ClientId,
+        author_name: String,
+        nick: String,
+        msg: String,
+        timestamp: u64,
+    },
+    /// `server::group_direct_message`
+    GroupDirectMessage {
+        author_id: lnpkg::ClientId,
+        recipients: Vec<lnpkg::ClientId>,
+        msg: String,
+    },
+    /// `server::delivery_status`
+    DeliveryStatus { recipient: String, status: String },
+    /// `server::delivery_report`
+    DeliveryReport {
+        delivered: Vec<lnpkg::ClientId>,
+        failed: Vec<lnpkg::ClientId>,
+    },
+    /// `server::list_clients`
+    ListClients(Vec<ClientSummary>),
+    /// `server::event_client_connected`
+    ClientConnected { id: lnpkg::ClientId, name: String },
+    /// `server::event_client_left`
+    ClientLeft { id: lnpkg::ClientId, name: String },
+    /// `server::event_client_renamed`
+    ClientRenamed {
+        id: lnpkg::ClientId,
+        old: String,
+        new: String,
+    },
+    /// `server::event_room_joined`
+    RoomJoined {
+        room: String,
+        id: lnpkg::ClientId,
+        name: String,
+    },
+    /// `server::event_room_parted`
+    RoomParted {
+        room: String,
+        id: lnpkg::ClientId,
+        name: String,
+    },
+    /// `server::event_moderation`
+    Moderation {
+        action: String,
+        client_id: Option<lnpkg::ClientId>,
+        target: String,
+        operator_id: lnpkg::ClientId,
+        duration: Option<u64>,
+    },
+    /// `server::list_rooms`
+    ListRooms(Vec<(String, usize)>),
+    /// `server::motd`
+    Motd(String),
+    /// `server::ping`
+    Ping,
+    /// `server::error`
+    Error { code: i128, reason: String },
+    /// `server::command_result`
+    CommandResult {
+        request_id: Option<i128>,
+        result: Result<Lpv, i128>,
+    },
+    /// `shared::welcome`
+    Welcome {
+        version: i128,
+        name: String,
+        software_version: String,
+        capabilities: Vec<String>,
+        /// Missing in the packages of the servers that predate the codecs, which only
+        /// speak `lnpkg`
+        codec: String,
+    },
+}
+
+impl TryFrom<Lnp> for ServerMessage {
+    type Error = ParseError;
+
+    fn try_from(mut pkg: Lnp) -> ParseResult<Self> {
+        if pkg.pkg_type == Lpty::Unknown {
+            return Err(ParseError::NoType);
+        }
+        match pkg.pkg_type {
+            Lpty::Message => Ok(Self::Message {
+                client_id: int(&pkg, "client")?,
+                msg: text(&pkg, "msg")?,
+                room: text(&pkg, "room")?,
+                msg_id: unsigned(&pkg, "msg_id")?,
+                timestamp: unsigned(&pkg, "timestamp")?,
+            }),
+            Lpty::SelfIdentity => Ok(Self::SelfIdentity {
+                id: int(&pkg, "id")?,
+                name: text(&pkg, "name")?,
+            }),
+            Lpty::Identity => Ok(Self::Identity {
+                id: int(&pkg, "id")?,
+                name: text(&pkg, "name")?,
+            }),
+            // The kind of direct message is told apart by its keys
+            Lpty::DirectMessage if pkg.content.contains_key("recipients") => {
+                Ok(Self::GroupDirectMessage {
+                    author_id: int(&pkg, "client")?,
+                    recipients: ids(&pkg, "recipients")?,
+                    msg: text(&pkg, "msg")?,
+                })
+            }
+            Lpty::DirectMessage if pkg.content.contains_key("to") => Ok(Self::NickDirectMessage {
+                author_id: int(&pkg, "client")?,
+                author_name: text(&pkg, "name")?,
+                nick: text(&pkg, "to")?,
+                msg: text(&pkg, "msg")?,
+                timestamp: unsigned(&pkg, "timestamp")?,
+            }),
+            Lpty::DirectMessage => Ok(Self::DirectMessage {
+                author_id: int(&pkg, "client")?,
+                author_name: text(&pkg, "name")?,
+                msg: text(&pkg, "msg")?,
+            }),
+            Lpty::EventClientConnected => Ok(Self::ClientConnected {
+                id: int(&pkg, "id")?,
+                name: text(&pkg, "name")?,
+            }),
+            Lpty::EventClientLeft => Ok(Self::ClientLeft {
+                id: int(&pkg, "id")?,
+                name: text(&pkg, "name")?,
+            }),
+            Lpty::Command => match text(&pkg, "command")?.as_str() {
+                "delivery_status" => Ok(Self::DeliveryStatus {
+                    recipient: text(&pkg, "recipient")?,
+                    status: text(&pkg, "status")?,
+                }),
+                "delivery_report" => Ok(Self::DeliveryReport {
+                    delivered: ids(&pkg, "delivered")?,
+                    failed: ids(&pkg, "failed")?,
+                }),
+                "list_clients" => {
+                    let ids = ids(&pkg, "ids")?;
+                    let names = parallel_list(&pkg, "names", ids.len())?;
+                    let connected_since = parallel_list(&pkg, "connected_since", ids.len())?;
+                    let idle = parallel_list(&pkg, "idle", ids.len())?;
+                    let mut clients = vec![];
+                    for (i, (id, name)) in ids.into_iter().zip(names).enumerate() {
+                        clients.push(ClientSummary {
+                            id,
+                            name,
+                            connected_since: optional_number(
+                                &connected_since[i],
+                                "connected_since",
+                            )?,
+                            idle: optional_number(&idle[i], "idle")?,
+                        });
+                    }
+                    Ok(Self::ListClients(clients))
+                }
+                "client_renamed" => Ok(Self::ClientRenamed {
+                    id: int(&pkg, "id")?,
+                    old: text(&pkg, "old")?,
+                    new: text(&pkg, "new")?,
+                }),
+                "room_joined" => Ok(Self::RoomJoined {
+                    room: text(&pkg, "room")?,
+                    id: int(&pkg, "id")?,
+                    name: text(&pkg, "name")?,
+                }),
+                "room_parted" => Ok(Self::RoomParted {
+                    room: text(&pkg, "room")?,
+                    id: int(&pkg, "id")?,
+                    name: text(&pkg, "name")?,
+                }),
+                "moderation" => Ok(Self::Moderation {
+                    action: text(&pkg, "action")?,
+                    client_id: optional_int(&pkg, "id")?,
+                    target: text(&pkg, "target")?,
+                    operator_id: int(&pkg, "by")?,
+                    duration: optional_int(&pkg, "duration")?
+                        .map(|d| to_unsigned(d, "duration"))
+                        .transpose()?,
+                }),
+                "list_rooms" => {
+                    let names = list(&pkg, "names")?;
+                    let members = parallel_list(&pkg, "members", names.len())?;
+                    let mut rooms = vec![];
+                    for (name, members) in names.into_iter().zip(members) {
+                        let members = members.parse().map_err(|_| ParseError::WrongType {
+                            key: "members",
+                            expected: "a list of amounts of members",
+                        })?;
+                        rooms.push((name, members));
+                    }
+                    Ok(Self::ListRooms(rooms))
+                }
+                "motd" => Ok(Self::Motd(text(&pkg, "msg")?)),
+                "ping" => Ok(Self::Ping),
+                "error" => Ok(Self::Error {
+                    code: int(&pkg, "code")?,
+                    reason: text(&pkg, "reason")?,
+                }),
+                "result" => {
+                    let request_id = optional_int(&pkg, "request_id")?;
+                    let result = match text(&pkg, "status")?.as_str() {
+                        // Lists of a single element are told apart by their `kind`
+                        "ok" if optional_text(&pkg, "kind")?.as_deref() == Some("list") => {
+                            Ok(Lpv::List(list(&pkg, "payload")?))
+                        }
+                        "ok" => Ok(pkg
+                            .content
+                            .remove("payload")
+                            .ok_or(ParseError::MissingKey("payload"))?),
+                        "err" => Err(int(&pkg, "code")?),
+                        _ => {
+                            return Err(ParseError::WrongType {
+                                key: "status",
+                                expected: "'ok' or 'err'",
+                            })
+                        }
+                    };
+                    Ok(Self::CommandResult { request_id, result })
+                }
+                "welcome" => Ok(Self::Welcome {
+                    version: int(&pkg, "version")?,
+                    name: text(&pkg, "name")?,
+                    software_version: text(&pkg, "software_version")?,
+                    capabilities: shared::capabilities(&pkg),
+                    codec: optional_text(&pkg, "codec")?.unwrap_or_else(|| "lnpkg".to_string()),
+                }),
+                command => Err(ParseError::UnknownCommand(command.to_string())),
+            },
+            _ => Err(ParseError::UnexpectedType),
+        }
+    }
+}
+
+impl From<ServerMessage> for Lnp {
+    fn from(msg: ServerMessage) -> Self {
+        match msg {
+            ServerMessage::Message {
+                client_id,
+                msg,
+                room,
+                msg_id,
+                timestamp,
+            } => server::msg(client_id, msg, room, msg_id, timestamp),
+            ServerMessage::SelfIdentity { id, name } => server::self_identity(id, name),
+            ServerMessage::Identity { id, name } => server::identity(id, name),
+            ServerMessage::DirectMessage {
+                author_id,
+                author_name,
+                msg,
+            } => server::direct_message(author_id, author_name, msg),
+            ServerMessage::NickDirectMessage {
+                author_id,
+                author_name,
+                nick,
+                msg,
+                timestamp,
+            } => server::nick_direct_message(author_id, author_name, nick, msg, timestamp),
+            ServerMessage::GroupDirectMessage {
+                author_id,
+                recipients,
+                msg,
+            } => server::group_direct_message(author_id, recipients, msg),
+            ServerMessage::DeliveryStatus { recipient, status } => {
+                server::delivery_status(recipient, &status)
+            }
+            ServerMessage::DeliveryReport { delivered, failed } => {
+                server::delivery_report(delivered, failed)
+            }
+            ServerMessage::ListClients(clients) => server::list_clients(clients),
+            ServerMessage::ClientConnected { id, name } => server::event_client_connected(id, name),
+            ServerMessage::ClientLeft { id, name } => server::event_client_left(id, name),
+            ServerMessage::ClientRenamed { id, old, new } => {
+                server::event_client_renamed(id, old, new)
+            }
+            ServerMessage::RoomJoined { room, id, name } => {
+                server::event_room_joined(room, id, name)
+            }
+            ServerMessage::RoomParted { room, id, name } => {
+                server::event_room_parted(room, id, name)
+            }
+            ServerMessage::Moderation {
+                action,
+                client_id,
+                target,
+                operator_id,
+                duration,
+            } => server::event_moderation(&action, client_id, target, operator_id, duration),
+            ServerMessage::ListRooms(rooms) => server::list_rooms(rooms),
+            ServerMessage::Motd(motd) => server::motd(motd),
+            ServerMessage::Ping => server::ping(),
+            ServerMessage::Error { code, reason } => server::error(code, reason),
+            ServerMessage::CommandResult { request_id, result } => {
+                server::command_result(request_id, result)
+            }
+            ServerMessage::Welcome {
+                version,
+                name,
+                software_version,
+                capabilities,
+                codec,
+            } => {
+                let mut pkg = shared::welcome(name, software_version, capabilities, codec);
+                pkg.content.insert("version".to_string(), Lpv::Int(version));
+                pkg
+            }
+        }
+    }
+}
+
+/// Text in the key specified. Numbers are accepted too, since a text made of digits
+/// can't be told apart from a number once sent.
+fn text(pkg: &Lnp, key: &'static str) -> ParseResult<String> {
+    match pkg.content.get(key) {
+        Some(Lpv::String(s)) => Ok(s.clone()),
+        Some(Lpv::Int(i)) => Ok(i.to_string()),
+        Some(_) => Err(ParseError::WrongType {
+            key,
+            expected: "a text",
+        }),
+        None => Err(ParseError::MissingKey(key)),
+    }
+}
+
+/// Same as `text`, but the key can be missing (*or `Null`*)
+fn optional_text(pkg: &Lnp, key: &'static str) -> ParseResult<Option<String>> {
+    match pkg.content.get(key) {
+        None | Some(Lpv::Null) => Ok(None),
+        Some(_) => text(pkg, key).map(Some),
+    }
+}
+
+fn int(pkg: &Lnp, key: &'static str) -> ParseResult<i128> {
+    match pkg.content.get(key) {
+        Some(Lpv::Int(i)) => Ok(*i),
+        Some(_) => Err(ParseError::WrongType {
+            key,
+            expected: "an integer",
+        }),
+        None => Err(ParseError::MissingKey(key)),
+    }
+}
+
+/// Same as `int`, but the key can be missing (*or `Null`*)
+fn optional_int(pkg: &Lnp, key: &'static str) -> ParseResult<Option<i128>> {
+    match pkg.content.get(key) {
+        None | Some(Lpv::Null) => Ok(None),
+        Some(_) => int(pkg, key).map(Some),
+    }
+}
+
+fn unsigned(pkg: &Lnp, key: &'static str) -> ParseResult<u64> {
+    to_unsigned(int(pkg, key)?, key)
+}
+
+fn to_unsigned(i: i128, key: &'static str) -> ParseResult<u64> {
+    u64::try_from(i).map_err(|_| ParseError::WrongType {
+        key,
+        expected: "a positive integer",
+    })
+}
+
+/// List in the key specified (*single element lists might be parsed as a plain value,
+/// and empty ones as an empty text or `Null`*)
+fn list(pkg: &Lnp, key: &'static str) -> ParseResult<Vec<String>> {
+    match pkg.content.get(key) {
+        Some(Lpv::List(l)) => Ok(l.clone()),
+        Some(Lpv::Null) => Ok(vec![]),
+        Some(Lpv::String(s)) if s.is_empty() => Ok(vec![]),
+        Some(value) => Ok(vec![value.to_string()]),
+        None => Err(ParseError::MissingKey(key)),
+    }
+}
+
+/// Same as `list`, but it must have the length specified (*it's the parallel list of
+/// another one*)
+fn parallel_list(pkg: &Lnp, key: &'static str, length: usize) -> ParseResult<Vec<String>> {
+    let l = list(pkg, key)?;
+    // An empty element can't be told apart from an empty list
+    if l.is_empty() && length == 1 {
+        return Ok(vec![String::new()]);
+    }
+    if l.len() != length {
+        return Err(ParseError::WrongType {
+            key,
+            expected: "a list as long as the others of the package",
+        });
+    }
+    Ok(l)
+}
+
+/// List of client ids in the key specified
+fn ids(pkg: &Lnp, key: &'static str) -> ParseResult<Vec<lnpkg::ClientId>> {
+    list(pkg, key)?
+        .iter()
+        .map(|id| id.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| ParseError::WrongType {
+            key,
+            expected: "a list of client ids",
+        })
+}
+
+/// Element of a list of optional numbers, where unknown values are empty
+fn optional_number(element: &str, key: &'static str) -> ParseResult<Option<u64>> {
+    if element.is_empty() {
+        return Ok(None);
+    }
+    element
+        .parse()
+        .map(Some)
+        .map_err(|_| ParseError::WrongType {
+            key,
+            expected: "a list of positive integers",
+        })
+}
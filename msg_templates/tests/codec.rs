@@ -0,0 +1,66 @@
+use msg_templates::codec::{self, Codec, CodecError};
+use msg_templates::{client, shared, Lnp, Lpty, Lpv};
+
+/// Encodes the package and decodes it back
+fn resend(codec: &dyn Codec, pkg: &Lnp) -> Lnp {
+    codec.decode(&codec.encode(pkg)).unwrap()
+}
+
+#[test]
+fn json_round_trip() {
+    let msg = client::msg(
+        "key=value: with \"quotes\"\nand lines".to_string(),
+        Some("lobby".to_string()),
+    );
+    let parsed = resend(&codec::JSON, &msg);
+    assert_eq!(msg.content, parsed.content);
+
+    let hello = shared::hello(
+        "client".to_string(),
+        "1.0.0".to_string(),
+        vec!["history".to_string()],
+        None,
+    );
+    let parsed = resend(&codec::JSON, &hello);
+    assert!(matches!(parsed.pkg_type, Lpty::Command));
+    assert_eq!(
+        Some(&Lpv::Int(shared::PROTOCOL_VERSION)),
+        parsed.content.get("version")
+    );
+    assert_eq!(vec!["history".to_string()], shared::capabilities(&parsed));
+    // A payload never contains the newline that ends its frame
+    assert!(!codec::JSON.encode(&msg).contains(&b'\n'));
+}
+
+#[test]
+fn json_decode() {
+    let parsed = codec::JSON
+        .decode(br#"{"type":"direct_message","to":3,"msg":"hi","extra":null}"#)
+        .unwrap();
+    assert!(matches!(parsed.pkg_type, Lpty::DirectMessage));
+    assert_eq!(Some(&Lpv::Int(3)), parsed.content.get("to"));
+    assert_eq!(Some(&Lpv::Null), parsed.content.get("extra"));
+
+    let parsed = codec::JSON.decode(br#"{"msg":"no type"}"#).unwrap();
+    assert!(matches!(parsed.pkg_type, Lpty::Unknown));
+
+    assert!(matches!(
+        codec::JSON.decode(b"[1, 2]"),
+        Err(CodecError::NonValidJson(_))
+    ));
+    assert!(matches!(
+        codec::JSON.decode(br#"{"type":"message","msg":true}"#),
+        Err(CodecError::NonValidJson(_))
+    ));
+}
+
+#[test]
+fn codec_selection() {
+    assert_eq!("json", codec::detect(b'{').name());
+    assert_eq!("lnpkg", codec::detect(0).name());
+    assert_eq!("lnpkg", codec::by_name("lnpkg").unwrap().name());
+    assert!(codec::by_name("xml").is_none());
+
+    let msg = client::msg("hi".to_string(), None);
+    assert_eq!(msg.content, resend(&codec::LNPKG, &msg).content);
+}
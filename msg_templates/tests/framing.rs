@@ -0,0 +1,91 @@
+use msg_templates::framing::{self, FrameDecoder, FrameError, Framing};
+
+#[test]
+fn coalesced_frames() {
+    let mut decoder = FrameDecoder::default();
+    let mut bytes = framing::encode_frame(b"type=msg:msg=first:").unwrap();
+    bytes.extend(framing::encode_frame(b"type=msg:msg=second:").unwrap());
+    decoder.push(&bytes);
+
+    assert_eq!(
+        Ok(Some(b"type=msg:msg=first:".to_vec())),
+        decoder.next_frame()
+    );
+    assert_eq!(
+        Ok(Some(b"type=msg:msg=second:".to_vec())),
+        decoder.next_frame()
+    );
+    assert_eq!(Ok(None), decoder.next_frame());
+}
+
+#[test]
+fn split_frame() {
+    let payload = vec![b'a'; 4000];
+    let bytes = framing::encode_frame(&payload).unwrap();
+    let mut decoder = FrameDecoder::default();
+
+    for chunk in bytes.chunks(3) {
+        assert_eq!(Ok(None), decoder.next_frame());
+        decoder.push(chunk);
+    }
+    assert_eq!(Ok(Some(payload)), decoder.next_frame());
+}
+
+#[test]
+fn frame_too_large() {
+    let mut decoder = FrameDecoder::new(8);
+    decoder.push(&framing::encode_frame(b"more than eight bytes").unwrap());
+
+    assert_eq!(
+        Err(FrameError::TooLarge { size: 21, max: 8 }),
+        decoder.next_frame()
+    );
+}
+
+#[test]
+fn frame_reader() {
+    let mut bytes = framing::encode_frame(b"first").unwrap();
+    bytes.extend(framing::encode_frame(b"").unwrap());
+    let mut reader = framing::FrameReader::new(bytes.as_slice(), 1024);
+
+    assert_eq!(Some(b"first".to_vec()), reader.read_frame().unwrap());
+    assert_eq!(Some(vec![]), reader.read_frame().unwrap());
+    assert_eq!(None, reader.read_frame().unwrap());
+}
+
+#[test]
+fn peek_non_whitespace() {
+    let bytes = b" \r\n\t{\"a\":1}\n";
+    let mut reader = framing::FrameReader::with_framing(bytes.as_slice(), Framing::Lines, 1024);
+    assert_eq!(Some(b'{'), reader.peek_non_whitespace().unwrap());
+    assert_eq!(Some(b"{\"a\":1}".to_vec()), reader.read_frame().unwrap());
+
+    let mut reader = framing::FrameReader::new(b"  \n".as_slice(), 1024);
+    assert_eq!(None, reader.peek_non_whitespace().unwrap());
+}
+
+#[test]
+fn line_frames() {
+    let mut decoder = FrameDecoder::with_framing(Framing::Lines, 1024);
+    decoder.push(b"{\"a\":1}\r\n\n{\"b\"");
+    assert_eq!(Ok(Some(b"{\"a\":1}".to_vec())), decoder.next_frame());
+    assert_eq!(Ok(None), decoder.next_frame());
+
+    decoder.push(b":2}\n");
+    assert_eq!(Ok(Some(b"{\"b\":2}".to_vec())), decoder.next_frame());
+    assert_eq!(Ok(b"{}\n".to_vec()), Framing::Lines.encode(b"{}"));
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn header_too_large() {
+    let size = u32::MAX as usize;
+    assert_eq!(Ok([0xff; 4]), framing::encode_header(size));
+    assert_eq!(
+        Err(FrameError::TooLarge {
+            size: size + 1,
+            max: size
+        }),
+        framing::encode_header(size + 1)
+    );
+}
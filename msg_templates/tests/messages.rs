@@ -0,0 +1,265 @@
+use msg_templates::messages::{ClientMessage, ParseError, Recipient, ServerMessage};
+use msg_templates::server::ClientSummary;
+use msg_templates::{Lnp, Lpty, Lpv};
+use std::collections::HashMap;
+
+/// Sends the package through its text form and parses it back
+fn resend(pkg: Lnp) -> Lnp {
+    Lnp::from_string(&pkg.to_string())
+}
+
+fn client_messages() -> Vec<ClientMessage> {
+    vec![
+        ClientMessage::Message {
+            msg: "hello there".to_string(),
+            room: None,
+        },
+        ClientMessage::Message {
+            msg: "hello there".to_string(),
+            room: Some("rust".to_string()),
+        },
+        ClientMessage::DirectMessage {
+            to: Recipient::Client(3),
+            msg: "psst".to_string(),
+        },
+        ClientMessage::DirectMessage {
+            to: Recipient::Group(vec![3, 4]),
+            msg: "psst".to_string(),
+        },
+        ClientMessage::DirectMessage {
+            to: Recipient::Group(vec![3]),
+            msg: "psst".to_string(),
+        },
+        ClientMessage::DirectMessage {
+            to: Recipient::Nick("alice".to_string()),
+            msg: "psst".to_string(),
+        },
+        ClientMessage::DirectMessage {
+            to: Recipient::Nick("123".to_string()),
+            msg: "psst".to_string(),
+        },
+        ClientMessage::Command {
+            command: "join".to_string(),
+            args: vec!["rust".to_string(), "now".to_string()],
+            request_id: Some(7),
+        },
+        ClientMessage::Command {
+            command: "whoami".to_string(),
+            args: vec![],
+            request_id: None,
+        },
+        ClientMessage::Pong,
+        ClientMessage::Hello {
+            version: 1,
+            name: "client".to_string(),
+            software_version: "1.0.0".to_string(),
+            capabilities: vec!["history".to_string(), "receipts".to_string()],
+            nick: Some("alice".to_string()),
+        },
+        ClientMessage::SelfIdentityRequest,
+        ClientMessage::IdentityRequest { id: 3 },
+    ]
+}
+
+fn server_messages() -> Vec<ServerMessage> {
+    vec![
+        ServerMessage::Message {
+            client_id: 1,
+            msg: "hello there".to_string(),
+            room: "lobby".to_string(),
+            msg_id: 10,
+            timestamp: 1700000000,
+        },
+        ServerMessage::SelfIdentity {
+            id: 1,
+            name: "alice".to_string(),
+        },
+        ServerMessage::Identity {
+            id: 2,
+            name: "bob".to_string(),
+        },
+        ServerMessage::DirectMessage {
+            author_id: 1,
+            author_name: "alice".to_string(),
+            msg: "psst".to_string(),
+        },
+        ServerMessage::NickDirectMessage {
+            author_id: 1,
+            author_name: "alice".to_string(),
+            nick: "bob".to_string(),
+            msg: "psst".to_string(),
+            timestamp: 1700000000,
+        },
+        ServerMessage::GroupDirectMessage {
+            author_id: 1,
+            recipients: vec![2, 3],
+            msg: "psst".to_string(),
+        },
+        ServerMessage::DeliveryStatus {
+            recipient: "bob".to_string(),
+            status: "queued".to_string(),
+        },
+        ServerMessage::DeliveryReport {
+            delivered: vec![2, 3],
+            failed: vec![],
+        },
+        ServerMessage::ListClients(vec![
+            ClientSummary {
+                id: 1,
+                name: "alice".to_string(),
+                connected_since: Some(1700000000),
+                idle: None,
+            },
+            ClientSummary {
+                id: 2,
+                name: "bob".to_string(),
+                connected_since: None,
+                idle: Some(5),
+            },
+        ]),
+        ServerMessage::ClientConnected {
+            id: 2,
+            name: "bob".to_string(),
+        },
+        ServerMessage::ClientLeft {
+            id: 2,
+            name: "bob".to_string(),
+        },
+        ServerMessage::ClientRenamed {
+            id: 2,
+            old: "bob".to_string(),
+            new: "robert".to_string(),
+        },
+        ServerMessage::RoomJoined {
+            room: "rust".to_string(),
+            id: 2,
+            name: "bob".to_string(),
+        },
+        ServerMessage::RoomParted {
+            room: "rust".to_string(),
+            id: 2,
+            name: "bob".to_string(),
+        },
+        ServerMessage::Moderation {
+            action: "ban".to_string(),
+            client_id: None,
+            target: "10.0.0.0/8".to_string(),
+            operator_id: 1,
+            duration: Some(60),
+        },
+        ServerMessage::ListRooms(vec![("lobby".to_string(), 2), ("rust".to_string(), 1)]),
+        ServerMessage::Motd("welcome".to_string()),
+        ServerMessage::Ping,
+        ServerMessage::Error {
+            code: msg_templates::error_code::UNKNOWN_COMMAND,
+            reason: "Unknown command.".to_string(),
+        },
+        ServerMessage::CommandResult {
+            request_id: Some(7),
+            result: Ok(Lpv::String("alice".to_string())),
+        },
+        ServerMessage::CommandResult {
+            request_id: Some(8),
+            result: Ok(Lpv::List(vec!["alice".to_string()])),
+        },
+        ServerMessage::CommandResult {
+            request_id: None,
+            result: Err(msg_templates::error_code::NON_VALID_COMMAND_USAGE),
+        },
+        ServerMessage::Welcome {
+            version: 1,
+            name: "socks".to_string(),
+            software_version: "0.1.0".to_string(),
+            capabilities: vec!["history".to_string(), "receipts".to_string()],
+            codec: "json".to_string(),
+        },
+    ]
+}
+
+#[test]
+fn client_round_trip() {
+    for (msg, expected) in client_messages().into_iter().zip(client_messages()) {
+        let parsed = ClientMessage::try_from(resend(msg.into()));
+        assert_eq!(Ok(expected), parsed);
+    }
+}
+
+#[test]
+fn server_round_trip() {
+    for (msg, expected) in server_messages().into_iter().zip(server_messages()) {
+        let parsed = ServerMessage::try_from(resend(msg.into()));
+        assert_eq!(Ok(expected), parsed);
+    }
+}
+
+#[test]
+fn one_element_lists() {
+    // Lists of a single element might arrive as the element itself
+    let mut pkg: Lnp = ClientMessage::DirectMessage {
+        to: Recipient::Group(vec![3]),
+        msg: "psst".to_string(),
+    }
+    .into();
+    pkg.content.insert("id".to_string(), Lpv::Int(3));
+    assert_eq!(
+        Ok(ClientMessage::DirectMessage {
+            to: Recipient::Group(vec![3]),
+            msg: "psst".to_string(),
+        }),
+        ClientMessage::try_from(pkg)
+    );
+
+    let mut pkg: Lnp = ServerMessage::CommandResult {
+        request_id: None,
+        result: Ok(Lpv::List(vec!["alice".to_string()])),
+    }
+    .into();
+    pkg.content
+        .insert("payload".to_string(), Lpv::String("alice".to_string()));
+    assert_eq!(
+        Ok(ServerMessage::CommandResult {
+            request_id: None,
+            result: Ok(Lpv::List(vec!["alice".to_string()])),
+        }),
+        ServerMessage::try_from(pkg)
+    );
+}
+
+#[test]
+fn parse_errors() {
+    let mut hm = HashMap::new();
+    hm.insert("room".to_string(), Lpv::String("lobby".to_string()));
+    let pkg = Lnp::from_hashmap(hm, Lpty::Message);
+    assert_eq!(
+        Err(ParseError::MissingKey("msg")),
+        ClientMessage::try_from(pkg)
+    );
+
+    let mut hm = HashMap::new();
+    hm.insert("id".to_string(), Lpv::String("alice".to_string()));
+    let pkg = Lnp::from_hashmap(hm, Lpty::Identity);
+    assert_eq!(
+        Err(ParseError::WrongType {
+            key: "id",
+            expected: "an integer"
+        }),
+        ClientMessage::try_from(pkg)
+    );
+
+    let mut hm = HashMap::new();
+    hm.insert("command".to_string(), Lpv::String("teleport".to_string()));
+    let pkg = Lnp::from_hashmap(hm, Lpty::Command);
+    assert_eq!(
+        Err(ParseError::UnknownCommand("teleport".to_string())),
+        ServerMessage::try_from(pkg)
+    );
+
+    assert_eq!(
+        Err(ParseError::NoType),
+        ClientMessage::try_from(Lnp::from_string("msg=hi:"))
+    );
+    assert_eq!(
+        Err(ParseError::UnexpectedType),
+        ClientMessage::try_from(Lnp::new(Lpty::EventClientLeft))
+    );
+}
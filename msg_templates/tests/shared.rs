@@ -0,0 +1,25 @@
+use msg_templates::shared::{self, capability};
+use msg_templates::Lnp;
+
+#[test]
+fn compatible_versions() {
+    assert!(shared::is_compatible(shared::PROTOCOL_VERSION));
+    assert!(!shared::is_compatible(shared::PROTOCOL_VERSION + 1));
+    assert!(!shared::is_compatible(shared::MIN_PROTOCOL_VERSION - 1));
+}
+
+#[test]
+fn hello_round_trip() {
+    let capabilities: Vec<String> = capability::ALL.iter().map(|c| c.to_string()).collect();
+    let hello = shared::hello(
+        "client".to_string(),
+        "1.0.0".to_string(),
+        capabilities.clone(),
+        Some("someone".to_string()),
+    );
+    let parsed = Lnp::from_string(&hello.to_string());
+
+    assert_eq!(Some("hello"), msg_templates::command_name(&parsed));
+    assert_eq!(capabilities, shared::capabilities(&parsed));
+    assert_eq!("someone", parsed.content["nick"].to_string());
+}
@@ -0,0 +1,281 @@
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    io::{self, BufRead, Write},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Range of IP addresses in CIDR notation (*eg. `10.0.0.0/8`*), a single address is
+/// a range whose prefix covers the whole address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Whether the address is inside the range (*IPv4 addresses are never inside IPv6
+    /// ranges, and vice versa*)
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = mask_v4(self.prefix);
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = mask_v6(self.prefix);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix(address: &IpAddr) -> u8 {
+        match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(address: IpAddr) -> Self {
+        Self {
+            network: address,
+            prefix: Self::max_prefix(&address),
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("Non valid IP address '{}'.", address))?;
+        let max_prefix = Self::max_prefix(&address);
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("Non valid prefix length '{}'.", prefix)),
+            },
+            None => max_prefix,
+        };
+
+        // The bits outside the prefix are cleared, so equal ranges compare equal
+        let network = match address {
+            IpAddr::V4(a) => IpAddr::V4((u32::from(a) & mask_v4(prefix)).into()),
+            IpAddr::V6(a) => IpAddr::V6((u128::from(a) & mask_v6(prefix)).into()),
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix == Self::max_prefix(&self.network) {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+/// Range of addresses that aren't allowed to connect
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub range: IpRange,
+    /// Moment (*in seconds since the UNIX epoch*) in which the ban expires, `None` if
+    /// it lasts until it's lifted.
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expires {
+            Some(expires) => write!(
+                f,
+                "{} (expires in {}s)",
+                self.range,
+                expires.saturating_sub(now())
+            ),
+            None => write!(f, "{}", self.range),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Decides which addresses can connect to the server: the ones inside the allow list
+/// (*every address if it's empty*) that haven't been banned.
+/// <br>The bans can be stored in a file, one per line, with the format
+/// `range<TAB>expires` (*`expires` is empty for bans without expiry*). The file is read
+/// when opening the list, and rewritten every time the bans change.
+#[derive(Debug, Default)]
+pub struct AccessList {
+    bans: Vec<Ban>,
+    allow: Vec<IpRange>,
+    path: Option<PathBuf>,
+}
+
+impl AccessList {
+    /// Access list whose bans are kept in memory, lost when the server stops
+    pub fn new(allow: Vec<IpRange>) -> Self {
+        Self {
+            bans: vec![],
+            allow,
+            path: None,
+        }
+    }
+
+    /// Opens the list whose bans are stored at the path specified, the file is created
+    /// once a ban is added.
+    pub fn open(path: impl Into<PathBuf>, allow: Vec<IpRange>) -> io::Result<Self> {
+        let path = path.into();
+        let mut bans = vec![];
+
+        if path.exists() {
+            for line in io::BufReader::new(fs::File::open(&path)?).lines() {
+                let line = line?;
+                let ban = match line.split_once('\t') {
+                    Some((range, expires)) => match (range.parse(), expires) {
+                        (Ok(range), "") => Some(Ban {
+                            range,
+                            expires: None,
+                        }),
+                        (Ok(range), expires) => expires.parse().ok().map(|expires| Ban {
+                            range,
+                            expires: Some(expires),
+                        }),
+                        _ => None,
+                    },
+                    None => None,
+                };
+                match ban {
+                    Some(ban) if !ban.is_expired() => bans.push(ban),
+                    Some(_) => (), // Expired
+                    None => eprintln!("Skipping malformed line in the bans file: {:?}", line),
+                }
+            }
+        }
+        Ok(Self {
+            bans,
+            allow,
+            path: Some(path),
+        })
+    }
+
+    /// Whether the address can connect to the server
+    pub fn is_allowed(&self, address: &IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|r| r.contains(address));
+        allowed
+            && !self
+                .bans
+                .iter()
+                .any(|ban| !ban.is_expired() && ban.range.contains(address))
+    }
+
+    /// Bans the range for the duration specified (*or until it's lifted, if there is no
+    /// duration*), replacing any previous ban of the same range. Durations whose expiry
+    /// can't be represented are rejected with an error of kind `InvalidInput`.
+    pub fn ban(&mut self, range: IpRange, duration: Option<Duration>) -> io::Result<()> {
+        let expires = match duration {
+            Some(duration) => match now().checked_add(duration.as_secs()) {
+                Some(expires) => Some(expires),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the ban lasts too long",
+                    ))
+                }
+            },
+            None => None,
+        };
+        let mut bans = self.bans.clone();
+        bans.retain(|ban| ban.range != range && !ban.is_expired());
+        bans.push(Ban { range, expires });
+        self.replace_bans(bans)
+    }
+
+    /// Lifts the ban of the range, returns `false` if it wasn't banned
+    pub fn unban(&mut self, range: &IpRange) -> io::Result<bool> {
+        let banned = self
+            .bans
+            .iter()
+            .any(|ban| ban.range == *range && !ban.is_expired());
+        let mut bans = self.bans.clone();
+        bans.retain(|ban| ban.range != *range && !ban.is_expired());
+        self.replace_bans(bans)?;
+        Ok(banned)
+    }
+
+    /// Bans that haven't expired yet
+    pub fn bans(&self) -> Vec<&Ban> {
+        self.bans.iter().filter(|ban| !ban.is_expired()).collect()
+    }
+
+    /// Saves the bans given and makes them the current ones, which are left as they were
+    /// if they can't be saved.
+    fn replace_bans(&mut self, bans: Vec<Ban>) -> io::Result<()> {
+        self.save(&bans)?;
+        self.bans = bans;
+        Ok(())
+    }
+
+    /// Rewrites the file of the list (*if any*) with the bans given. They're written
+    /// to a temporary file first, which then replaces the old one, so the bans aren't lost
+    /// if the server stops while writing them.
+    fn save(&self, bans: &[Ban]) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = fs::File::create(&temp_path)?;
+        for ban in bans.iter() {
+            let expires = ban.expires.map(|e| e.to_string()).unwrap_or_default();
+            writeln!(file, "{}\t{}", ban.range, expires)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
@@ -0,0 +1,180 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+/// Identifier of an account, unlike `lnpkg::ClientId` (*which only lasts for a session*)
+/// it stays the same between connections.
+pub type AccountId = u64;
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: AccountId,
+    pub username: String,
+    /// Salted hash of the password, in the PHC string format
+    pub password_hash: String,
+}
+
+#[derive(Debug)]
+/// Errors that can occur when creating or accessing accounts
+pub enum AccountError {
+    /// There is already an account with the username specified
+    UsernameTaken,
+    /// The account doesn't exist, or the password doesn't match
+    InvalidCredentials,
+    /// The username contains characters that the storage backend can't store
+    InvalidUsername,
+    /// The account couldn't be stored (*or read*) from the storage backend
+    Storage(io::Error),
+}
+
+/// Storage of the accounts registered in the server
+pub trait AccountStore: Send {
+    /// Stores a new account, returning it along with the id assigned to it
+    fn create(&mut self, username: &str, password_hash: String) -> Result<Account, AccountError>;
+    /// Returns the account with the username specified
+    fn find(&self, username: &str) -> Option<Account>;
+
+    /// Creates an account storing the salted hash of the password given
+    fn register(&mut self, username: &str, password: &str) -> Result<Account, AccountError> {
+        if self.find(username).is_some() {
+            return Err(AccountError::UsernameTaken);
+        }
+        self.create(username, hash_password(password))
+    }
+
+    /// Returns the account if the password given matches the one of the account
+    fn login(&self, username: &str, password: &str) -> Result<Account, AccountError> {
+        match self.find(username) {
+            Some(account) if verify_password(password, &account.password_hash) => Ok(account),
+            _ => Err(AccountError::InvalidCredentials),
+        }
+    }
+}
+
+/// Hashes the password with a randomly generated salt
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Cannot hash password.")
+        .to_string()
+}
+
+/// Checks if the password matches the hash given (*generated by `hash_password`*)
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Accounts kept in memory, lost when the server stops
+#[derive(Default)]
+pub struct MemoryAccountStore {
+    accounts: HashMap<String, Account>,
+    last_id: AccountId,
+}
+
+impl AccountStore for MemoryAccountStore {
+    fn create(&mut self, username: &str, password_hash: String) -> Result<Account, AccountError> {
+        if self.accounts.contains_key(username) {
+            return Err(AccountError::UsernameTaken);
+        }
+        self.last_id += 1;
+        let account = Account {
+            id: self.last_id,
+            username: username.to_string(),
+            password_hash,
+        };
+        self.accounts.insert(username.to_string(), account.clone());
+        Ok(account)
+    }
+
+    fn find(&self, username: &str) -> Option<Account> {
+        self.accounts.get(username).cloned()
+    }
+}
+
+/// Accounts stored in a file, one per line, with the format `id<TAB>username<TAB>hash`.
+/// The file is read completely when opening the store, and new accounts are appended to it.
+pub struct FileAccountStore {
+    path: PathBuf,
+    memory: MemoryAccountStore,
+}
+
+impl FileAccountStore {
+    /// Opens the store at the path specified, the file is created if it doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut memory = MemoryAccountStore::default();
+
+        if path.exists() {
+            for line in io::BufReader::new(fs::File::open(&path)?).lines() {
+                let line = line?;
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() != 3 {
+                    eprintln!("Skipping malformed line in the accounts file: {:?}", line);
+                    continue;
+                }
+                let id: AccountId = match fields[0].parse() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        eprintln!("Skipping account with non valid id: {:?}", line);
+                        continue;
+                    }
+                };
+                memory.last_id = memory.last_id.max(id);
+                memory.accounts.insert(
+                    fields[1].to_string(),
+                    Account {
+                        id,
+                        username: fields[1].to_string(),
+                        password_hash: fields[2].to_string(),
+                    },
+                );
+            }
+        }
+        Ok(Self { path, memory })
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn create(&mut self, username: &str, password_hash: String) -> Result<Account, AccountError> {
+        if username.contains(['\t', '\n']) {
+            return Err(AccountError::InvalidUsername);
+        }
+        if self.memory.find(username).is_some() {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        // Written before adding it to memory, so the account can't be used unless it's
+        // been stored
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(AccountError::Storage)?;
+        writeln!(
+            file,
+            "{}\t{}\t{}",
+            self.memory.last_id + 1,
+            username,
+            password_hash
+        )
+        .map_err(AccountError::Storage)?;
+        self.memory.create(username, password_hash)
+    }
+
+    fn find(&self, username: &str) -> Option<Account> {
+        self.memory.find(username)
+    }
+}
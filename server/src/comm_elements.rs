@@ -1,13 +1,30 @@
+use crate::access::{AccessList, IpRange};
+use crate::accounts::{
+    hash_password, verify_password, Account, AccountError, AccountId, AccountStore,
+    MemoryAccountStore,
+};
+use crate::config::Config;
+use crate::history::History;
+use crate::nicks::{nick_key, same_nick, NickError, NickRules};
+use crate::storage::{MemoryStorage, OfflineMessage, Storage, StorageResult};
 use msg_templates;
+use msg_templates::codec::{self, Codec};
+use msg_templates::framing;
+use msg_templates::messages::{ClientMessage, ParseError, Recipient};
+use msg_templates::shared::{self, capability};
 use std::{
-    collections::HashMap,
-    io,
-    io::{Read, Write},
+    collections::{HashMap, HashSet},
+    io::{self, Write},
     net,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// Different errors that can occur when elements of the server interact between each other
 pub enum ClientInputError {
     /// This variant appears when the message sent by a user has an invalid format
@@ -28,118 +45,759 @@ pub enum ClientInputError {
     NonValidCommandUsage,
     /// An error occurred in the server internals functioning.
     InternalServerError,
+    /// The client tried to register an account with a username that's already in use.
+    UsernameTaken,
+    /// The client tried to log in with a username or password that doesn't match.
+    InvalidCredentials,
+    /// The client tried to do something reserved to operators.
+    PermissionDenied,
+    /// The client tried to send a message while being muted by an operator.
+    Muted,
+    /// The client tried to do something that requires being logged in to an account.
+    NotLoggedIn,
+    /// The nickname chosen by the client breaks the rules of the server (*length or characters*).
+    InvalidNickname,
+    /// The nickname chosen by the client is reserved.
+    ReservedNickname,
+    /// The nickname chosen by the client is being used by another client.
+    NicknameTaken,
+}
+
+impl From<NickError> for ClientInputError {
+    fn from(e: NickError) -> Self {
+        match e {
+            NickError::TooShort | NickError::TooLong | NickError::NonValidCharacter(_) => {
+                Self::InvalidNickname
+            }
+            NickError::Reserved => Self::ReservedNickname,
+        }
+    }
+}
+
+impl From<ParseError> for ClientInputError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::NoType => Self::NoMessageType,
+            ParseError::UnexpectedType | ParseError::UnknownCommand(_) => Self::UnknownMessageType,
+            ParseError::MissingKey(_) | ParseError::WrongType { .. } => Self::NonValidFormat,
+        }
+    }
+}
+
+impl From<AccountError> for ClientInputError {
+    fn from(e: AccountError) -> Self {
+        match e {
+            AccountError::UsernameTaken => Self::UsernameTaken,
+            AccountError::InvalidCredentials => Self::InvalidCredentials,
+            AccountError::InvalidUsername => Self::NonValidCommandUsage,
+            AccountError::Storage(e) => {
+                eprintln!("Error in the account storage: {:?}", e);
+                Self::InternalServerError
+            }
+        }
+    }
+}
+
+/// What the server does with the client after one of its inputs resulted in an error
+#[derive(Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// The error is sent back to the client, which stays connected.
+    Reply,
+    /// The error is sent back to the client, and it gets disconnected once the same
+    /// error has occurred the number of times specified.
+    FatalAfter(usize),
+}
+
+impl ClientInputError {
+    /// Stable code that identifies the error in the `server::error` package
+    pub fn code(&self) -> i128 {
+        use msg_templates::error_code::*;
+        match self {
+            Self::NonValidFormat => NON_VALID_FORMAT,
+            Self::NoMessageType => NO_MESSAGE_TYPE,
+            Self::UnknownMessageType => UNKNOWN_MESSAGE_TYPE,
+            Self::UnknownUser => UNKNOWN_USER,
+            Self::ResourceNotAvailable => RESOURCE_NOT_AVAILABLE,
+            Self::UnknownCommand => UNKNOWN_COMMAND,
+            Self::NonValidCommandUsage => NON_VALID_COMMAND_USAGE,
+            Self::InternalServerError => INTERNAL_SERVER_ERROR,
+            Self::UsernameTaken => USERNAME_TAKEN,
+            Self::InvalidCredentials => INVALID_CREDENTIALS,
+            Self::PermissionDenied => PERMISSION_DENIED,
+            Self::Muted => MUTED,
+            Self::NotLoggedIn => NOT_LOGGED_IN,
+            Self::InvalidNickname => INVALID_NICKNAME,
+            Self::ReservedNickname => RESERVED_NICKNAME,
+            Self::NicknameTaken => NICKNAME_TAKEN,
+        }
+    }
+
+    /// Human readable explanation of the error, sent to the client along with its code
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::NonValidFormat => "The package sent has a non valid format.",
+            Self::NoMessageType => "The package sent doesn't specify its type.",
+            Self::UnknownMessageType => "The type of the package sent is not known by the server.",
+            Self::UnknownUser => "The user referenced doesn't exist.",
+            Self::ResourceNotAvailable => "The resource requested is not available.",
+            Self::UnknownCommand => "Unknown command.",
+            Self::NonValidCommandUsage => "Non valid usage of the command.",
+            Self::InternalServerError => "An internal error occurred in the server.",
+            Self::UsernameTaken => "The username is already taken.",
+            Self::InvalidCredentials => "Non valid username or password.",
+            Self::PermissionDenied => "Only operators can do that.",
+            Self::Muted => "You have been muted by an operator.",
+            Self::NotLoggedIn => "You have to log in first.",
+            Self::InvalidNickname => {
+                "The nickname is too short, too long, or contains characters not allowed."
+            }
+            Self::ReservedNickname => "The nickname is reserved.",
+            Self::NicknameTaken => "The nickname is already in use.",
+        }
+    }
+
+    /// Policy table that decides whether the client gets disconnected because of the error
+    pub fn policy(&self) -> ErrorPolicy {
+        match self {
+            // Malformed packages usually mean that the client doesn't speak the protocol
+            Self::NonValidFormat | Self::NoMessageType | Self::UnknownMessageType => {
+                ErrorPolicy::FatalAfter(3)
+            }
+            // Guessing passwords (*of `oper`, accounts or nicknames*)
+            Self::InvalidCredentials => ErrorPolicy::FatalAfter(5),
+            Self::UnknownUser
+            | Self::ResourceNotAvailable
+            | Self::UnknownCommand
+            | Self::NonValidCommandUsage
+            | Self::InternalServerError
+            | Self::UsernameTaken
+            | Self::PermissionDenied
+            | Self::Muted
+            | Self::NotLoggedIn
+            | Self::InvalidNickname
+            | Self::ReservedNickname
+            | Self::NicknameTaken => ErrorPolicy::Reply,
+        }
+    }
+
+    /// Whether the error was caused by a package that the server couldn't understand
+    pub fn is_malformed_package(&self) -> bool {
+        matches!(
+            self,
+            Self::NonValidFormat | Self::NoMessageType | Self::UnknownMessageType
+        )
+    }
 }
 
 pub struct Client {
     pub name: String,
+    /// Stream of the client, only used for shutting down the connection, packages
+    /// are sent through the `outbox`
     pub stream: net::TcpStream,
+    /// Outbound queue, the packages in it get written to the stream by a dedicated thread
+    pub outbox: Outbox,
+    /// Moment since which the outbound queue has been full (*`None` if it isn't*), it's
+    /// cleared once the writer has taken packages out of it (*see `Server::check_slow_consumers`*)
+    pub outbox_full_since: Option<Instant>,
+    /// Moment in which the client connected to the server
+    pub connected_since: SystemTime,
+    /// Moment in which the client sent its last package
+    pub last_activity: Instant,
+    /// Account the client has logged in with (*if any*)
+    pub account_id: Option<AccountId>,
+    /// Rooms the client is a member of
+    pub rooms: HashSet<String>,
+    /// Whether the client has been granted the operator role (*see the `oper` command*)
+    pub is_operator: bool,
+    /// Whether the client has been muted by an operator
+    pub mute: Option<Mute>,
+    /// Capabilities negotiated in the handshake (*see `msg_templates::shared::capability`*)
+    pub capabilities: Vec<String>,
+    /// Codec spoken by the client, picked in the handshake (*see `msg_templates::codec`*)
+    pub codec: &'static dyn Codec,
+}
+
+/// Time during which a muted client can't send messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mute {
+    /// Until an operator unmutes the client
+    Indefinite,
+    /// Until the moment specified
+    Until(Instant),
+}
+
+/// Package waiting in the outbound queue of a client
+pub struct Outgoing {
+    /// Frame carrying the package, already encoded with the codec of the client
+    pub msg: Vec<u8>,
+    /// Package put in another outbound queue once `msg` has been written to the stream
+    /// (*eg. the delivery receipt for the author of a direct message*)
+    pub receipt: Option<(Outbox, Vec<u8>)>,
+}
+
+/// Sending end of the outbound queue of a client, which keeps count of the packages in it
+#[derive(Clone)]
+pub struct Outbox {
+    sender: mpsc::SyncSender<Outgoing>,
+    /// Packages in the queue that haven't been written to the stream yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    /// Puts the package in the queue without blocking
+    pub fn try_send(&self, outgoing: Outgoing) -> Result<(), mpsc::TrySendError<Outgoing>> {
+        // Counted beforehand, so the writer can't take it out before it's counted
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let result = self.sender.try_send(outgoing);
+        if result.is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Number of packages waiting in the queue
+    pub fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Client {
+    /// Creates the client and spawns the thread that writes the packages of its outbound
+    /// queue (*of the size specified*) to the stream.
+    pub fn new(name: String, stream: net::TcpStream, queue_size: usize) -> Self {
+        let (sender, queue) = mpsc::sync_channel::<Outgoing>(queue_size);
+        let outbox = Outbox {
+            sender,
+            queued: Arc::new(AtomicUsize::new(0)),
+        };
+        let queued = Arc::clone(&outbox.queued);
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            // Ends once the client is dropped (*closing the queue*), or the stream fails
+            for outgoing in queue {
+                let written = writer.write_all(&outgoing.msg).and_then(|_| writer.flush());
+                queued.fetch_sub(1, Ordering::SeqCst);
+                if let Err(e) = written {
+                    eprintln!("Error writing to client stream: {:?}", e);
+                    break;
+                }
+                if let Some((outbox, receipt)) = outgoing.receipt {
+                    // The other client might be gone, or not reading its packages
+                    let _ = outbox.try_send(Outgoing {
+                        msg: receipt,
+                        receipt: None,
+                    });
+                }
+            }
+        });
+
+        Self {
+            name,
+            stream,
+            outbox,
+            outbox_full_since: None,
+            connected_since: SystemTime::now(),
+            last_activity: Instant::now(),
+            account_id: None,
+            rooms: HashSet::from([msg_templates::LOBBY.to_string()]),
+            is_operator: false,
+            mute: None,
+            capabilities: vec![],
+            codec: &codec::LNPKG,
+        }
+    }
+
+    /// Whether the capability was negotiated in the handshake
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Whether the client is muted at this moment
+    pub fn is_muted(&self) -> bool {
+        match self.mute {
+            None => false,
+            Some(Mute::Indefinite) => true,
+            Some(Mute::Until(until)) => Instant::now() < until,
+        }
+    }
+
+    /// Puts the package in the outbound queue of the client without blocking, returns
+    /// an error of kind `WouldBlock` if the queue is full.
+    pub fn enqueue(&mut self, msg: &lnpkg::LnPkg) -> io::Result<()> {
+        self.push(Outgoing {
+            msg: self.codec.encode_frame(msg)?,
+            receipt: None,
+        })
+    }
+
+    /// Same as `enqueue`, but once the package has been written to the stream, the
+    /// receipt (*a frame already encoded for its recipient*) is put in the outbound queue given.
+    pub fn enqueue_with_receipt(
+        &mut self,
+        msg: &lnpkg::LnPkg,
+        receipt_outbox: Outbox,
+        receipt: Vec<u8>,
+    ) -> io::Result<()> {
+        self.push(Outgoing {
+            msg: self.codec.encode_frame(msg)?,
+            receipt: Some((receipt_outbox, receipt)),
+        })
+    }
+
+    fn push(&mut self, outgoing: Outgoing) -> io::Result<()> {
+        match self.outbox.try_send(outgoing) {
+            Ok(()) => {
+                self.outbox_full_since = None;
+                Ok(())
+            }
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.outbox_full_since.get_or_insert_with(Instant::now);
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "outbound queue full",
+                ))
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "writer of the client stopped",
+            )),
+        }
+    }
+}
+
+/// Whether a client owns the nickname it asked for in `chnick` (*see `PasswordWork::Chnick`*)
+#[derive(Debug, Clone, PartialEq)]
+enum NickOwnership {
+    /// Nobody owns the nickname, and no secret was given
+    Unclaimed,
+    /// The secret given matches the one the nickname was claimed with
+    Owner,
+    /// Nobody owns the nickname yet, it's claimed with the hash of the secret given once
+    /// the client is using it
+    Claim(String),
+}
+
+/// Packages queued for a client right after it connects (*the welcome and the identity*),
+/// so the outbound queues must be able to hold them
+pub const HANDSHAKE_PACKAGES: usize = 2;
+
+/// Amount of guest names tried before giving up on naming a client (*see `Server::guest_name`*)
+pub const MAX_GUEST_NAMES: usize = 100;
+
+/// What is left to do with a package once `Server::handle_client_input` has handled it
+pub enum InputOutcome {
+    /// Nothing, the package has been handled completely
+    Done,
+    /// The command sent needs a password hashed or verified (*see `PasswordWork`*)
+    Password(PasswordCommand),
+    /// The command sent failed, the error has already been reported in its result
+    /// package, but it still counts towards the `ErrorPolicy` of the error
+    CommandFailed(ClientInputError),
+}
+
+/// Command that can't be completed until the password given has been hashed or verified,
+/// which Argon2 makes slow on purpose. The work is done by `PasswordWork::run` without
+/// holding the lock of the server, and the command is completed by
+/// `Server::complete_password_command`.
+pub struct PasswordCommand {
+    pub request_id: Option<i128>,
+    pub work: PasswordWork,
+}
+
+pub enum PasswordWork {
+    /// `register`, the password gets hashed
+    Register { username: String, password: String },
+    /// `login`, the password gets verified against the one of the account (*if it exists*)
+    Login {
+        account: Option<Account>,
+        password: String,
+    },
+    /// `chnick` with a secret, which gets verified against the hash the nickname was
+    /// claimed with, or hashed to claim the nickname
+    Chnick {
+        nick: String,
+        secret: String,
+        secret_hash: Option<String>,
+    },
+}
+
+/// Password work of a `PasswordCommand` already done, see `PasswordCommand::run`
+pub struct PasswordCommandDone {
+    request_id: Option<i128>,
+    result: Result<PasswordOutcome, ClientInputError>,
+}
+
+impl PasswordCommand {
+    /// Does the password work of the command, which must be done without holding the
+    /// lock of the server
+    pub fn run(self) -> PasswordCommandDone {
+        PasswordCommandDone {
+            request_id: self.request_id,
+            result: self.work.run(),
+        }
+    }
+}
+
+/// Result of `PasswordWork::run`
+enum PasswordOutcome {
+    Register {
+        username: String,
+        password_hash: String,
+    },
+    Login(Account),
+    Chnick {
+        nick: String,
+        /// Hash the nickname was claimed with when the work started
+        secret_hash: Option<String>,
+        ownership: NickOwnership,
+    },
+}
+
+impl PasswordWork {
+    fn run(self) -> Result<PasswordOutcome, ClientInputError> {
+        match self {
+            Self::Register { username, password } => Ok(PasswordOutcome::Register {
+                username,
+                password_hash: hash_password(&password),
+            }),
+            Self::Login { account, password } => match account {
+                Some(account) if verify_password(&password, &account.password_hash) => {
+                    Ok(PasswordOutcome::Login(account))
+                }
+                _ => Err(ClientInputError::InvalidCredentials),
+            },
+            Self::Chnick {
+                nick,
+                secret,
+                secret_hash,
+            } => {
+                let ownership = match &secret_hash {
+                    Some(hash) if verify_password(&secret, hash) => NickOwnership::Owner,
+                    Some(_) => return Err(ClientInputError::InvalidCredentials),
+                    None => NickOwnership::Claim(hash_password(&secret)),
+                };
+                Ok(PasswordOutcome::Chnick {
+                    nick,
+                    secret_hash,
+                    ownership,
+                })
+            }
+        }
+    }
+}
+
+/// What the server does with a client that doesn't read its packages fast enough,
+/// causing its outbound queue to get full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// The packages that don't fit in the queue get dropped.
+    DropMessages,
+    /// The packages that don't fit get dropped, and the client gets disconnected once
+    /// its queue has stayed full for the duration specified.
+    DisconnectAfter(Duration),
+}
+
+/// Settings of the outbound queues of the clients
+pub struct OutboundConfig {
+    /// Maximum amount of packages waiting to be written for each client
+    pub queue_size: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 256,
+            slow_consumer: SlowConsumerPolicy::DisconnectAfter(Duration::from_secs(10)),
+        }
+    }
+}
+
+/// Summary of the delivery of a message broadcasted to every client
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    /// Clients that got the message queued
+    pub delivered: Vec<lnpkg::ClientId>,
+    /// Clients whose outbound queue was full, so the message was dropped
+    pub dropped: Vec<lnpkg::ClientId>,
+    /// Clients whose connection failed, which have been disconnected
+    pub evicted: Vec<lnpkg::ClientId>,
+}
+
+/// Settings of the liveness checks ("*R U alive?*") done by the server
+pub struct HeartbeatConfig {
+    /// Time without receiving anything from a client before a `ping` is sent to it
+    pub interval: Duration,
+    /// Time without receiving anything from a client before it's considered dead
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
 }
 
 pub struct Server {
     pub clients: HashMap<lnpkg::ClientId, Client>,
+    pub heartbeat: HeartbeatConfig,
+    pub outbound: OutboundConfig,
+    pub accounts: Box<dyn AccountStore>,
+    /// Maximum amount of clients connected at once (*`0` means that there is no limit*)
+    pub max_clients: usize,
+    /// Maximum size (*in bytes*) of a single package sent by a client
+    pub max_message_size: usize,
+    /// Message of the day, sent to every client that connects
+    pub motd: Option<String>,
+    /// Password of the `oper` command (*nobody can become an operator if it's `None`*)
+    pub oper_password: Option<String>,
+    /// Addresses that are allowed to connect, and the ones banned
+    pub access: AccessList,
+    /// Last messages sent to the rooms
+    pub history: History,
+    /// Amount of messages of the history sent to each client that connects
+    pub history_replay: usize,
+    /// State that survives restarts (*see `Server::set_storage`*)
+    pub storage: Box<dyn Storage>,
+    /// Rules that the nicknames must follow
+    pub nick_rules: NickRules,
     last_id: lnpkg::ClientId,
 }
 
+impl Default for Server {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
 impl Server {
-    pub fn default() -> Self {
+    /// Creates a server with the settings specified (*accounts are kept in memory,
+    /// `config.accounts_file` is not opened*)
+    pub fn from_config(config: &Config) -> Self {
         Self {
             clients: HashMap::new(),
+            heartbeat: HeartbeatConfig {
+                interval: config.heartbeat_interval(),
+                timeout: config.heartbeat_timeout(),
+            },
+            outbound: OutboundConfig {
+                queue_size: config.queue_size,
+                slow_consumer: match config.slow_consumer_timeout {
+                    0 => SlowConsumerPolicy::DropMessages,
+                    secs => SlowConsumerPolicy::DisconnectAfter(Duration::from_secs(secs)),
+                },
+            },
+            accounts: Box::new(MemoryAccountStore::default()),
+            max_clients: config.max_clients,
+            max_message_size: config.max_message_size,
+            motd: config.motd.clone(),
+            oper_password: config.oper_password.clone(),
+            access: AccessList::new(config.allow.clone()),
+            history: History::new(config.history_size),
+            history_replay: config.history_replay,
+            storage: Box::new(MemoryStorage::default()),
+            nick_rules: NickRules::from_config(config),
             last_id: 0,
         }
     }
+
+    /// Whether the server has reached its maximum amount of clients
+    pub fn is_full(&self) -> bool {
+        self.max_clients != 0 && self.clients.len() >= self.max_clients
+    }
+
     pub fn add_client(&mut self, c: Client) -> lnpkg::ClientId {
         self.last_id += 1;
         self.clients.insert(self.last_id, c);
+        if let Err(e) = self.storage.save_last_client_id(self.last_id) {
+            eprintln!("Couldn't store the last client id: {}", e);
+        }
         self.last_id
     }
 
-    pub fn broadcast_msg(&mut self, msg: &[u8]) -> io::Result<()> {
-        let mut i = self.clients.iter_mut();
-        loop {
-            if let Some(kp) = i.next() {
-                kp.1.stream.write(&msg)?;
-            } else {
-                break;
+    /// Replaces the storage of the server, restoring the state stored in it (*the
+    /// history and the last client id*)
+    pub fn set_storage(&mut self, storage: Box<dyn Storage>) -> StorageResult<()> {
+        let capacity = self.history.capacity();
+        self.history = History::restore(
+            capacity,
+            storage.load_messages(capacity)?,
+            storage.last_message_id()?,
+        );
+        self.last_id = storage.load_last_client_id()?;
+        self.storage = storage;
+        Ok(())
+    }
+
+    /// Enqueues the message for every client connected. Clients whose queue is full
+    /// miss the message (*see `SlowConsumerPolicy`*), and clients whose connection
+    /// failed get disconnected (*broadcasting `event_client_left` for each of them*).
+    pub fn broadcast_msg(&mut self, msg: &lnpkg::LnPkg) -> BroadcastReport {
+        self.broadcast_to(msg, |_| true)
+    }
+
+    /// Same as `broadcast_msg`, but only to the members of the room specified
+    pub fn broadcast_to_room(&mut self, room: &str, msg: &lnpkg::LnPkg) -> BroadcastReport {
+        self.broadcast_to(msg, |client| client.rooms.contains(room))
+    }
+
+    /// Same as `broadcast_msg`, but only to the clients that match the filter given
+    pub fn broadcast_to<F: Fn(&Client) -> bool>(
+        &mut self,
+        msg: &lnpkg::LnPkg,
+        filter: F,
+    ) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        for (id, client) in self.clients.iter_mut() {
+            if !filter(client) {
+                continue;
+            }
+            match client.enqueue(msg) {
+                Ok(()) => report.delivered.push(*id),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    eprintln!("Outbound queue of client {} is full, message dropped.", id);
+                    report.dropped.push(*id);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't send message to client {}: {:?}", id, e);
+                    report.evicted.push(*id);
+                }
             }
         }
-        Ok(())
+
+        for id in report.evicted.iter() {
+            let _ = self.disconnect_and_notify(*id);
+        }
+        report
     }
-    pub fn send_msg(&mut self, client_id: &lnpkg::ClientId, msg: &[u8]) -> io::Result<usize> {
+
+    /// Enqueues the message for the client specified
+    pub fn send_msg(&mut self, client_id: &lnpkg::ClientId, msg: &lnpkg::LnPkg) -> io::Result<()> {
         if !self.clients.contains_key(client_id) {
             return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, ""));
         } else {
-            self.clients.get_mut(client_id).unwrap().stream.write(msg)
+            self.clients.get_mut(client_id).unwrap().enqueue(msg)
         }
     }
 
-    /// Handles the input of the client, and returns a `Result` type containing an `Ok(())` to
-    /// represent a success parsing and execution of the client's input, or an `Err(ClientInputError)`
+    /// Enqueues the message for the client specified, once it has been written to its
+    /// stream, the receipt is sent to `receipt_to` (*unless it doesn't have the `receipts`
+    /// capability*).
+    pub fn send_msg_with_receipt(
+        &mut self,
+        client_id: &lnpkg::ClientId,
+        msg: &lnpkg::LnPkg,
+        receipt_to: &lnpkg::ClientId,
+        receipt: &lnpkg::LnPkg,
+    ) -> io::Result<()> {
+        let (receipt_outbox, receipt) = match self.clients.get(receipt_to) {
+            Some(client) if client.has_capability(capability::RECEIPTS) => {
+                (client.outbox.clone(), client.codec.encode_frame(receipt)?)
+            }
+            Some(_) => return self.send_msg(client_id, msg),
+            None => return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "")),
+        };
+        match self.clients.get_mut(client_id) {
+            Some(client) => client.enqueue_with_receipt(msg, receipt_outbox, receipt),
+            None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "")),
+        }
+    }
+
+    /// Handles the input of the client, and returns a `Result` type containing an `Ok(InputOutcome)`
+    /// to represent a success parsing and execution of the client's input, or an `Err(ClientInputError)`
     pub fn handle_client_input(
         &mut self,
         author_id: lnpkg::ClientId,
         msg: &[u8],
-    ) -> Result<(), ClientInputError> {
-        let msg = if let Ok(m) = String::from_utf8(msg.to_vec()) {
-            m
-        } else {
-            println!(
-                "server::comm_elements::Server::handle_client> Non utf-8 valid format: {:?}",
-                msg
-            );
-            return Err(ClientInputError::NonValidFormat);
+    ) -> Result<InputOutcome, ClientInputError> {
+        let codec = match self.clients.get_mut(&author_id) {
+            Some(author) => {
+                author.last_activity = Instant::now();
+                author.codec
+            }
+            None => &codec::LNPKG,
+        };
+        let msg = match codec.decode(msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!(
+                    "server::comm_elements::Server::handle_client> Non valid format ({}): {:?}",
+                    e, msg
+                );
+                return Err(ClientInputError::NonValidFormat);
+            }
         };
 
-        let parsed_message = lnpkg::LnPkg::from_string(&msg);
+        let parsed_message = match ClientMessage::try_from(msg) {
+            Ok(parsed_message) => parsed_message,
+            Err(e) => {
+                println!("Client {} sent a non valid package: {}", author_id, e);
+                return Err(e.into());
+            }
+        };
 
-        // Return error if the type of the message its unknown
-        if parsed_message.pkg_type == lnpkg::LnPkgType::Unknown {
-            println!("Client sent message without type, '{:?}'", parsed_message);
-            return Err(ClientInputError::NoMessageType);
+        // Muted clients can still use commands, but they can't talk
+        let is_talking = matches!(
+            parsed_message,
+            ClientMessage::Message { .. } | ClientMessage::DirectMessage { .. }
+        );
+        if is_talking && self.clients.get(&author_id).is_some_and(|c| c.is_muted()) {
+            println!("Client {} tried to talk while muted", author_id);
+            return Err(ClientInputError::Muted);
         }
 
-        let result: Result<(), ClientInputError> = match parsed_message.pkg_type {
-            lnpkg::LnPkgType::Message => {
-                if !parsed_message.exist(&["msg"]) {
-                    eprintln!(
-                        "The `Message` didn't contain the `msg` key. {:?}",
-                        parsed_message.content
-                    );
-                    return Err(ClientInputError::NonValidFormat);
+        let result: Result<(), ClientInputError> = match parsed_message {
+            ClientMessage::Message { msg, room } => {
+                let room = room.unwrap_or_else(|| msg_templates::LOBBY.to_string());
+                if !self.clients[&author_id].rooms.contains(&room) {
+                    println!("Client {} isn't a member of the room {}", author_id, room);
+                    return Err(ClientInputError::ResourceNotAvailable);
                 }
 
-                self.broadcast_msg(
-                    msg_templates::server::msg(
-                        author_id,
-                        parsed_message.content["msg"].to_string(),
-                    )
-                    .as_bytes()
-                    .as_slice(),
-                ).unwrap(); // TODO: Give this better error handling
+                let entry = self.history.push(author_id, room.clone(), msg);
+                if let Err(e) = self.storage.save_message(&entry) {
+                    eprintln!("Couldn't store the message {}: {}", entry.id, e);
+                }
+                self.broadcast_to_room(&room, &entry.to_package());
                 Ok(())
             }
-            lnpkg::LnPkgType::DirectMessage => {
-                // Check the integrity of the message
-                if !parsed_message.exist(&["msg", "id"]) {
-                    println!(
-                        "Direct message has incorrect format: {:?}",
-                        parsed_message.content
-                    );
-                    return Err(ClientInputError::NonValidFormat);
-                }
-
-                let destination_id: lnpkg::ClientId = match parsed_message.content["id"] {
-                    lnpkg::LnPkgValue::Int(i) => i,
-                    _ => {
-                        println!("The destination id provided by the client for the direct message wasn't an integer. {:?}", parsed_message.content["id"]);
-                        return Err(ClientInputError::NonValidFormat);
+            ClientMessage::DirectMessage { to, msg } => {
+                let destination_id: lnpkg::ClientId = match to {
+                    Recipient::Client(id) => id,
+                    Recipient::Group(ids) => {
+                        // Repeated recipients get the message once
+                        let mut recipients: Vec<lnpkg::ClientId> = vec![];
+                        for id in ids {
+                            if !recipients.contains(&id) {
+                                recipients.push(id);
+                            }
+                        }
+                        return self
+                            .send_group_direct_message(author_id, recipients, msg)
+                            .map(|_| InputOutcome::Done);
+                    }
+                    Recipient::Nick(nick) => {
+                        // Addressed to a nickname (*which might not be connected*)
+                        return self
+                            .send_nick_direct_message(author_id, nick, msg)
+                            .map(|_| InputOutcome::Done);
                     }
                 };
 
+                let author_name = self.clients[&author_id].name.clone();
+                let template = msg_templates::server::direct_message(author_id, author_name, msg);
+                let receipt =
+                    msg_templates::server::delivery_status(destination_id.to_string(), "delivered");
+
                 // Check for errors
-                match self.send_msg(
-                    &destination_id,
-                    parsed_message.content["msg"].to_string().as_bytes(),
-                ) {
+                match self.send_msg_with_receipt(&destination_id, &template, &author_id, &receipt) {
                     Err(e) => {
                         match e.kind() {
                             std::io::ErrorKind::AddrNotAvailable => {
@@ -159,39 +817,51 @@ impl Server {
 
                 Ok(())
             }
-            lnpkg::LnPkgType::Command => {
-                // Check package vality
-                if !parsed_message.exist(&["args", "command"]) {
-                    return Err(ClientInputError::NonValidFormat);
-                }
-
-                let arguments: Vec<String>;
-                if let lnpkg::LnPkgValue::List(l) = &parsed_message.content["args"] {
-                    arguments = l.into_iter().map(|x| x.to_owned()).collect();
-                } else if let lnpkg::LnPkgValue::String(s) = &parsed_message.content["args"] {
-                    arguments = vec![s.clone()];
-                } else {
-                    eprintln!("Command didn't have a list or a string as arguments");
-                    return Err(ClientInputError::NonValidFormat);
-                }
-
-                return self.execute_client_command(
-                    author_id,
-                    parsed_message.content["command"].to_string(),
-                    arguments,
-                );
+            ClientMessage::Pong => {
+                // Answers to pings don't need any more handling (*the activity of the
+                // client has already been registered*)
+                Ok(())
             }
-            lnpkg::LnPkgType::SelfIdentity => {
+            ClientMessage::Hello { .. } => {
+                println!("Client {} sent a hello after the handshake", author_id);
+                Err(ClientInputError::UnknownCommand)
+            }
+            ClientMessage::Command {
+                command,
+                args,
+                request_id,
+            } => {
+                // The outcome of the command (*even if it's an error*) is reported
+                // through the result package
+                let result = match self.password_work(author_id, &command, &args) {
+                    Ok(Some(work)) => {
+                        return Ok(InputOutcome::Password(PasswordCommand { request_id, work }))
+                    }
+                    Ok(None) => self.execute_client_command(author_id, command, args),
+                    Err(e) => Err(e),
+                };
+                return self.send_command_result(author_id, request_id, result);
+            }
+            ClientMessage::SelfIdentityRequest => {
                 let template = msg_templates::server::self_identity(author_id, self.clients[&author_id].name.clone());
-                self.send_msg(&author_id, template.as_bytes().as_slice()).unwrap(); // TODO: Give this better error handling
+                self.send_msg(&author_id, &template)
+                    .map_err(|_| ClientInputError::InternalServerError)?;
                 Ok(())
             }
-            lnpkg::LnPkgType::Identity => {
-                todo!()
-            }
-            lnpkg::LnPkgType::Unknown | _ => {
-                eprintln!("Message with unknown lnpkg type.");
-                return Err(ClientInputError::UnknownMessageType);
+            ClientMessage::IdentityRequest { id: target_id } => {
+                let target_name = match self.clients.get(&target_id) {
+                    Some(target) => target.name.clone(),
+                    None => return Err(ClientInputError::UnknownUser),
+                };
+                let template = msg_templates::server::identity(target_id, target_name);
+                if let Err(e) = self.send_msg(&author_id, &template) {
+                    eprintln!(
+                        "Couldn't send the identity of {} to {}: {:?}",
+                        target_id, author_id, e
+                    );
+                    return Err(ClientInputError::InternalServerError);
+                }
+                Ok(())
             }
         };
 
@@ -200,49 +870,983 @@ impl Server {
             println!("{:?}", e);
             return Err(e);
         } else {
-            Ok(())
+            Ok(InputOutcome::Done)
+        }
+    }
+
+    /// Sends the result package of a command to the client that sent it
+    fn send_command_result(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        request_id: Option<i128>,
+        result: Result<lnpkg::LnPkgValue, ClientInputError>,
+    ) -> Result<InputOutcome, ClientInputError> {
+        let outcome = match &result {
+            Ok(_) => InputOutcome::Done,
+            Err(e) => {
+                println!("Command sent by {} failed: {:?}", client_id, e);
+                InputOutcome::CommandFailed(*e)
+            }
+        };
+        let template =
+            msg_templates::server::command_result(request_id, result.map_err(|e| e.code()));
+        if let Err(e) = self.send_msg(&client_id, &template) {
+            eprintln!("Couldn't send the command result to {}: {:?}", client_id, e);
+            return Err(ClientInputError::InternalServerError);
+        }
+        Ok(outcome)
+    }
+
+    /// Password work needed by the command (*`None` if it doesn't need any*), the
+    /// arguments are checked beforehand, so the work isn't done in vain.
+    fn password_work(
+        &self,
+        client_id: lnpkg::ClientId,
+        command: &str,
+        arguments: &[String],
+    ) -> Result<Option<PasswordWork>, ClientInputError> {
+        match (command, arguments.first(), arguments.get(1)) {
+            ("register", Some(username), Some(password))
+                if !username.is_empty() && !password.is_empty() =>
+            {
+                if self.accounts.find(username).is_some() {
+                    return Err(ClientInputError::UsernameTaken);
+                }
+                Ok(Some(PasswordWork::Register {
+                    username: username.clone(),
+                    password: password.clone(),
+                }))
+            }
+            ("register", _, _) => Err(ClientInputError::NonValidCommandUsage),
+            ("login", Some(username), Some(password)) => Ok(Some(PasswordWork::Login {
+                account: self.accounts.find(username),
+                password: password.clone(),
+            })),
+            ("login", _, _) => Err(ClientInputError::NonValidCommandUsage),
+            ("chnick", Some(nick), Some(secret)) if !secret.is_empty() => {
+                self.check_nick(client_id, nick)?;
+                Ok(Some(PasswordWork::Chnick {
+                    nick: nick.clone(),
+                    secret: secret.clone(),
+                    secret_hash: self.nick_secret(nick)?,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Completes the command whose password work has been done (*see `PasswordCommand`*),
+    /// sending its result package to the client.
+    pub fn complete_password_command(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        done: PasswordCommandDone,
+    ) -> Result<InputOutcome, ClientInputError> {
+        let result = match done.result {
+            Ok(outcome) => self.complete_password_outcome(client_id, outcome),
+            Err(e) => Err(e),
+        };
+        self.send_command_result(client_id, done.request_id, result)
+    }
+
+    fn complete_password_outcome(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        outcome: PasswordOutcome,
+    ) -> Result<lnpkg::LnPkgValue, ClientInputError> {
+        match outcome {
+            PasswordOutcome::Register {
+                username,
+                password_hash,
+            } => {
+                // Fails if the username was taken meanwhile
+                let account = self.accounts.create(&username, password_hash)?;
+                println!("Client {} registered the account {}", client_id, account.id);
+                self.log_in(client_id, account.id)
+            }
+            PasswordOutcome::Login(account) => {
+                println!("Client {} logged in as account {}", client_id, account.id);
+                self.log_in(client_id, account.id)
+            }
+            PasswordOutcome::Chnick {
+                nick,
+                secret_hash,
+                ownership,
+            } => {
+                // The nickname might have been claimed meanwhile
+                if self.nick_secret(&nick)? != secret_hash {
+                    return Err(ClientInputError::InvalidCredentials);
+                }
+                self.finish_chnick(client_id, nick, ownership)
+            }
+        }
+    }
+
+    /// Sends the message to every recipient along with the full list of recipients (*so
+    /// replies can go to the whole group*), and reports to the author which recipients
+    /// got it and which didn't.
+    pub fn send_group_direct_message(
+        &mut self,
+        author_id: lnpkg::ClientId,
+        recipients: Vec<lnpkg::ClientId>,
+        msg: String,
+    ) -> Result<(), ClientInputError> {
+        if recipients.is_empty() {
+            return Err(ClientInputError::NonValidFormat);
+        }
+
+        let template =
+            msg_templates::server::group_direct_message(author_id, recipients.clone(), msg);
+        let mut delivered: Vec<lnpkg::ClientId> = vec![];
+        let mut failed: Vec<lnpkg::ClientId> = vec![];
+        for recipient in recipients {
+            match self.send_msg(&recipient, &template) {
+                Ok(()) => delivered.push(recipient),
+                Err(e) => {
+                    println!(
+                        "Group direct message couldn't be sent to {}: {:?}",
+                        recipient, e
+                    );
+                    failed.push(recipient);
+                }
+            }
+        }
+
+        let report = msg_templates::server::delivery_report(delivered, failed);
+        self.send_msg(&author_id, &report)
+            .map_err(|_| ClientInputError::InternalServerError)
+    }
+
+    /// Sends the message to the client with the nickname specified, or stores it until
+    /// the owner of the nickname takes it again if nobody is using it (*as long as it
+    /// has been claimed*). The author gets a `delivery_status` package once the message
+    /// has been written or queued.
+    pub fn send_nick_direct_message(
+        &mut self,
+        author_id: lnpkg::ClientId,
+        nick: String,
+        msg: String,
+    ) -> Result<(), ClientInputError> {
+        let author_name = self
+            .clients
+            .get(&author_id)
+            .ok_or(ClientInputError::UnknownUser)?
+            .name
+            .clone();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let recipient_id = self
+            .clients
+            .iter()
+            .find(|(_, client)| same_nick(&client.name, &nick))
+            .map(|(id, _)| *id);
+        match recipient_id {
+            Some(recipient_id) => {
+                // The author gets the receipt once the message has been written
+                let template = msg_templates::server::nick_direct_message(
+                    author_id,
+                    author_name,
+                    nick.clone(),
+                    msg,
+                    timestamp,
+                );
+                let receipt = msg_templates::server::delivery_status(nick, "delivered");
+                self.send_msg_with_receipt(&recipient_id, &template, &author_id, &receipt)
+                    .map_err(|_| ClientInputError::InternalServerError)
+            }
+            None => {
+                match self.storage.nick_secret(&nick_key(&nick)) {
+                    Ok(Some(_)) => (),
+                    Ok(None) => return Err(ClientInputError::UnknownUser), // Nobody owns it
+                    Err(e) => {
+                        eprintln!("Couldn't read the owner of the nickname {}: {}", nick, e);
+                        return Err(ClientInputError::InternalServerError);
+                    }
+                }
+                let offline_message = OfflineMessage {
+                    recipient: nick_key(&nick),
+                    author_id,
+                    author_name,
+                    timestamp,
+                    msg,
+                };
+                if let Err(e) = self.storage.queue_direct_message(&offline_message) {
+                    eprintln!("Couldn't store a direct message for {}: {}", nick, e);
+                    return Err(ClientInputError::InternalServerError);
+                }
+                if !self.clients[&author_id].has_capability(capability::RECEIPTS) {
+                    return Ok(());
+                }
+                let template = msg_templates::server::delivery_status(nick, "queued");
+                self.send_msg(&author_id, &template)
+                    .map_err(|_| ClientInputError::InternalServerError)
+            }
+        }
+    }
+
+    /// Hash of the secret the nickname was claimed with (*`None` if nobody owns it*)
+    fn nick_secret(&self, nick: &str) -> Result<Option<String>, ClientInputError> {
+        self.storage.nick_secret(&nick_key(nick)).map_err(|e| {
+            eprintln!("Couldn't read the owner of the nickname {}: {}", nick, e);
+            ClientInputError::InternalServerError
+        })
+    }
+
+    /// Stores the hash of the secret that proves the ownership of the nickname
+    fn claim_nick(&mut self, nick: &str, secret_hash: &str) -> Result<(), ClientInputError> {
+        if let Err(e) = self.storage.set_nick_secret(&nick_key(nick), secret_hash) {
+            eprintln!("Couldn't claim the nickname {}: {}", nick, e);
+            return Err(ClientInputError::InternalServerError);
+        }
+        println!("The nickname {} has been claimed", nick);
+        Ok(())
+    }
+
+    /// Renames the client once the ownership of the nickname has been checked, claiming
+    /// it, and delivering its offline messages if the client owns it
+    fn finish_chnick(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        nick: String,
+        ownership: NickOwnership,
+    ) -> Result<lnpkg::LnPkgValue, ClientInputError> {
+        self.change_name(client_id, nick.clone())?;
+        // Only claimed once the rename has succeeded
+        if let NickOwnership::Claim(secret_hash) = &ownership {
+            self.claim_nick(&nick, secret_hash)?;
+        }
+        if ownership != NickOwnership::Unclaimed {
+            self.deliver_offline_messages(client_id, &nick)?;
         }
+        Ok(lnpkg::LnPkgValue::String(nick))
+    }
+
+    /// Sends to the client the direct messages addressed to the nickname while nobody
+    /// was using it, returning the amount of messages sent. Only the ones that fit in the
+    /// outbound queue of the client (*leaving room for the result of the command*) are
+    /// taken from the storage, the rest are kept for the next delivery.
+    pub fn deliver_offline_messages(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        nick: &str,
+    ) -> Result<usize, ClientInputError> {
+        let client = self
+            .clients
+            .get(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        let free = self
+            .outbound
+            .queue_size
+            .saturating_sub(client.outbox.len())
+            .saturating_sub(1);
+        let messages = self
+            .storage
+            .take_direct_messages(&nick_key(nick), free)
+            .map_err(|e| {
+                eprintln!("Couldn't read the direct messages for {}: {}", nick, e);
+                ClientInputError::InternalServerError
+            })?;
+        for msg in messages.iter() {
+            let template = msg_templates::server::nick_direct_message(
+                msg.author_id,
+                msg.author_name.clone(),
+                msg.recipient.clone(),
+                msg.msg.clone(),
+                msg.timestamp,
+            );
+            if let Err(e) = self.send_msg(&client_id, &template) {
+                eprintln!(
+                    "Couldn't deliver a direct message to {}: {:?}",
+                    client_id, e
+                );
+                return Err(ClientInputError::InternalServerError);
+            }
+        }
+        Ok(messages.len())
+    }
+
+    /// Sends the `server::error` package that corresponds to the error given to the client
+    pub fn send_error(
+        &mut self,
+        client_id: &lnpkg::ClientId,
+        error: &ClientInputError,
+    ) -> io::Result<()> {
+        let template = msg_templates::server::error(error.code(), error.reason().to_string());
+        self.send_msg(client_id, &template)
     }
 
     /// Disconnects an specific client from the server and removes it from the `self.clients` hashmap
+    /// (*The stream of the client gets shut down, which ends the thread handling it*)
     pub fn disconnect_client(&mut self, client_id: lnpkg::ClientId) -> Result<(), ClientInputError> {
         if !self.clients.contains_key(&client_id) {
             return Err(ClientInputError::UnknownUser);
         } else {
-            let _ = self.clients.remove_entry(&client_id);
+            if let Some((_, client)) = self.clients.remove_entry(&client_id) {
+                let _ = client.stream.shutdown(net::Shutdown::Both);
+            }
             return Ok(());
         }
     }
 
-    /// Executes commands sent by the client that might take changes on the server
+    /// Disconnects the client specified and broadcasts the `event_client_left` package
+    /// to the rest of clients.
+    pub fn disconnect_and_notify(
+        &mut self,
+        client_id: lnpkg::ClientId,
+    ) -> Result<(), ClientInputError> {
+        let client_name = match self.clients.get(&client_id) {
+            Some(client) => client.name.clone(),
+            None => return Err(ClientInputError::UnknownUser),
+        };
+        self.disconnect_client(client_id)?;
+        self.broadcast_msg(&msg_templates::server::event_client_left(
+            client_id,
+            client_name,
+        ));
+        println!("Client ({}) disconnected from the server.", client_id);
+        Ok(())
+    }
+
+    /// Disconnects the clients whose outbound queue has been full for longer than
+    /// allowed by the `SlowConsumerPolicy`. Returns the ids of the clients disconnected.
+    pub fn check_slow_consumers(&mut self) -> Vec<lnpkg::ClientId> {
+        // The queues that have been emptied a bit aren't full anymore, even if nothing
+        // has been sent to their clients since then
+        let queue_size = self.outbound.queue_size;
+        for client in self.clients.values_mut() {
+            if client.outbox.len() < queue_size {
+                client.outbox_full_since = None;
+            }
+        }
+
+        let max_full_time = match self.outbound.slow_consumer {
+            SlowConsumerPolicy::DropMessages => return vec![],
+            SlowConsumerPolicy::DisconnectAfter(duration) => duration,
+        };
+        let slow: Vec<lnpkg::ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .outbox_full_since
+                    .is_some_and(|since| since.elapsed() >= max_full_time)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in slow.iter() {
+            println!(
+                "Client {} is not reading its packages, disconnecting it.",
+                id
+            );
+            let _ = self.disconnect_and_notify(*id);
+        }
+        slow
+    }
+
+    /// Disconnects every client (*without notifying the rest*)
+    pub fn disconnect_all(&mut self) {
+        let ids: Vec<lnpkg::ClientId> = self.clients.keys().copied().collect();
+        for id in ids {
+            let _ = self.disconnect_client(id);
+        }
+    }
+
+    /// Pings the clients that have been idle for longer than the heartbeat interval,
+    /// and disconnects the ones that have been idle for longer than the timeout.
+    /// Returns the ids of the clients disconnected.
+    pub fn check_heartbeats(&mut self) -> Vec<lnpkg::ClientId> {
+        let mut dead: Vec<lnpkg::ClientId> = vec![];
+        let mut idle: Vec<lnpkg::ClientId> = vec![];
+        for (id, client) in self.clients.iter() {
+            let elapsed = client.last_activity.elapsed();
+            if elapsed >= self.heartbeat.timeout {
+                dead.push(*id);
+            } else if elapsed >= self.heartbeat.interval {
+                idle.push(*id);
+            }
+        }
+
+        let ping = msg_templates::server::ping();
+        for id in idle {
+            if let Err(e) = self.send_msg(&id, &ping) {
+                eprintln!("Couldn't ping client {}: {:?}", id, e);
+            }
+        }
+        for id in dead.iter() {
+            println!("Client {} didn't answer the pings in time.", id);
+            let _ = self.disconnect_and_notify(*id);
+        }
+        dead
+    }
+
+    /// Executes commands sent by the client that might take changes on the server,
+    /// returning the payload of the result package sent back to the client
     pub fn execute_client_command(
         &mut self,
         client_id: lnpkg::ClientId,
         command: String,
         arguments: Vec<String>,
-    ) -> Result<(), ClientInputError> {
+    ) -> Result<lnpkg::LnPkgValue, ClientInputError> {
         println!("Executing command {} sent by {}", command, client_id);
 
         let command = command.as_str();
         return match command {
             "chnick" => {
-                if let Some(new_name) = arguments.get(0) {
-                    self.change_name(client_id, new_name.to_string())?
-                } else {
-                    return Err(ClientInputError::NonValidCommandUsage)
+                let new_name = match arguments.first() {
+                    Some(new_name) => new_name.clone(),
+                    None => return Err(ClientInputError::NonValidCommandUsage),
+                };
+                // The secrets of the nicknames are checked by `PasswordWork::Chnick`, so
+                // only the nicknames that nobody owns can be taken here
+                if self.nick_secret(&new_name)?.is_some() {
+                    return Err(ClientInputError::InvalidCredentials);
                 }
-                Ok(())
+                self.finish_chnick(client_id, new_name, NickOwnership::Unclaimed)
             }
             "whoami" => {
-                let template = msg_templates::server::self_identity(client_id, self.clients[&client_id].name.clone());
-                self.send_msg(&client_id, template.as_bytes().as_slice()).unwrap(); // TODO: Give this better error handling
-                Ok(())
+                let name = self.clients[&client_id].name.clone();
+                let template = msg_templates::server::self_identity(client_id, name.clone());
+                self.send_msg(&client_id, &template)
+                    .map_err(|_| ClientInputError::InternalServerError)?;
+                Ok(lnpkg::LnPkgValue::String(name))
+            }
+            "users" => {
+                let template = msg_templates::server::list_clients(self.list_clients());
+                self.send_msg(&client_id, &template)
+                    .map_err(|_| ClientInputError::InternalServerError)?;
+                Ok(lnpkg::LnPkgValue::Int(self.clients.len() as i128))
+            }
+            "join" => {
+                let room = match arguments.first() {
+                    Some(room) if !room.is_empty() => room.clone(),
+                    _ => return Err(ClientInputError::NonValidCommandUsage),
+                };
+                self.join_room(client_id, room.clone())?;
+                Ok(lnpkg::LnPkgValue::String(room))
+            }
+            "part" => {
+                let room = match arguments.first() {
+                    Some(room) => room.clone(),
+                    None => return Err(ClientInputError::NonValidCommandUsage),
+                };
+                self.part_room(client_id, room.clone())?;
+                Ok(lnpkg::LnPkgValue::String(room))
+            }
+            "rooms" => {
+                let rooms = self.list_rooms();
+                let count = rooms.len();
+                let template = msg_templates::server::list_rooms(rooms);
+                self.send_msg(&client_id, &template)
+                    .map_err(|_| ClientInputError::InternalServerError)?;
+                Ok(lnpkg::LnPkgValue::Int(count as i128))
+            }
+            "history" => {
+                let n: usize = arguments
+                    .first()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(ClientInputError::NonValidCommandUsage)?;
+                let before: Option<u64> = match arguments.get(1) {
+                    Some(id) => Some(
+                        id.parse()
+                            .map_err(|_| ClientInputError::NonValidCommandUsage)?,
+                    ),
+                    None => None,
+                };
+                let count = self.send_history(client_id, n, before)?;
+                Ok(lnpkg::LnPkgValue::Int(count as i128))
+            }
+            "set" => {
+                let (key, value) = match (arguments.first(), arguments.get(1)) {
+                    (Some(k), Some(v)) if !k.is_empty() => (k, v),
+                    _ => return Err(ClientInputError::NonValidCommandUsage),
+                };
+                let account_id = self.account_of(client_id)?;
+                if let Err(e) = self.storage.set_setting(account_id, key, value) {
+                    eprintln!("Couldn't store a setting of account {}: {}", account_id, e);
+                    return Err(ClientInputError::InternalServerError);
+                }
+                Ok(lnpkg::LnPkgValue::String(value.clone()))
+            }
+            "settings" => {
+                let account_id = self.account_of(client_id)?;
+                match self.storage.settings(account_id) {
+                    Ok(settings) => Ok(lnpkg::LnPkgValue::List(
+                        settings
+                            .iter()
+                            .map(|(key, value)| format!("{}={}", key, value))
+                            .collect(),
+                    )),
+                    Err(e) => {
+                        eprintln!(
+                            "Couldn't read the settings of account {}: {}",
+                            account_id, e
+                        );
+                        Err(ClientInputError::InternalServerError)
+                    }
+                }
+            }
+            "oper" => {
+                let password = arguments
+                    .first()
+                    .ok_or(ClientInputError::NonValidCommandUsage)?;
+                match &self.oper_password {
+                    None => return Err(ClientInputError::PermissionDenied),
+                    Some(oper_password) if !constant_time_eq(oper_password, password) => {
+                        return Err(ClientInputError::InvalidCredentials)
+                    }
+                    Some(_) => (),
+                }
+                let client = self
+                    .clients
+                    .get_mut(&client_id)
+                    .ok_or(ClientInputError::UnknownUser)?;
+                client.is_operator = true;
+                println!("Client {} is now an operator", client_id);
+                Ok(lnpkg::LnPkgValue::String("operator".to_string()))
+            }
+            "kick" => {
+                self.require_operator(client_id)?;
+                let target_id = self.moderation_target(arguments.first())?;
+                self.kick(client_id, target_id)?;
+                Ok(lnpkg::LnPkgValue::Int(target_id))
+            }
+            "ban" => {
+                self.require_operator(client_id)?;
+                // The target can be a client (*its address gets banned*) or a range
+                let argument = arguments
+                    .first()
+                    .ok_or(ClientInputError::NonValidCommandUsage)?;
+                let (target_id, range) = match argument.parse::<lnpkg::ClientId>() {
+                    Ok(_) => {
+                        let target_id = self.moderation_target(Some(argument))?;
+                        let address = self.client_address(target_id)?;
+                        (Some(target_id), IpRange::from(address))
+                    }
+                    Err(_) => match argument.parse::<IpRange>() {
+                        Ok(range) => (None, range),
+                        Err(_) => return Err(ClientInputError::NonValidCommandUsage),
+                    },
+                };
+                let duration = parse_duration(arguments.get(1))?;
+                self.ban(client_id, range, target_id, duration)?;
+                Ok(lnpkg::LnPkgValue::String(range.to_string()))
+            }
+            "unban" => {
+                self.require_operator(client_id)?;
+                let range: IpRange = arguments
+                    .first()
+                    .and_then(|a| a.parse().ok())
+                    .ok_or(ClientInputError::NonValidCommandUsage)?;
+                self.unban(client_id, range)?;
+                Ok(lnpkg::LnPkgValue::String(range.to_string()))
+            }
+            "bans" => {
+                self.require_operator(client_id)?;
+                let bans = self.access.bans().iter().map(|b| b.to_string()).collect();
+                Ok(lnpkg::LnPkgValue::List(bans))
+            }
+            "mute" => {
+                self.require_operator(client_id)?;
+                let target_id = self.moderation_target(arguments.first())?;
+                let duration = parse_duration(arguments.get(1))?;
+                self.mute(client_id, target_id, duration)?;
+                Ok(lnpkg::LnPkgValue::Int(target_id))
+            }
+            "unmute" => {
+                self.require_operator(client_id)?;
+                let target_id = self.moderation_target(arguments.first())?;
+                self.unmute(client_id, target_id)?;
+                Ok(lnpkg::LnPkgValue::Int(target_id))
             }
             _ => return Err(ClientInputError::UnknownCommand)
         }
     }
 
-    /// Changes the name of the client specified
+    /// Associates the account specified with the client, returning the account id
+    /// as the payload for the result package
+    fn log_in(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        account_id: AccountId,
+    ) -> Result<lnpkg::LnPkgValue, ClientInputError> {
+        match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.account_id = Some(account_id);
+                Ok(lnpkg::LnPkgValue::Int(account_id as i128))
+            }
+            None => Err(ClientInputError::UnknownUser),
+        }
+    }
+
+    /// Sends to the client up to `n` of the last messages of the history sent to the rooms
+    /// it's a member of (*only the ones older than `before`, if specified*), returning the
+    /// amount of messages sent. No more messages are sent than the ones that fit in the
+    /// outbound queue of the client.
+    pub fn send_history(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        n: usize,
+        before: Option<u64>,
+    ) -> Result<usize, ClientInputError> {
+        let client = self
+            .clients
+            .get(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        let rooms = &client.rooms;
+        let n = n.min(self.outbound.queue_size.saturating_sub(client.outbox.len()));
+        let packages: Vec<msg_templates::Lnp> = self
+            .history
+            .last(n, before, |entry| rooms.contains(&entry.room))
+            .iter()
+            .map(|entry| entry.to_package())
+            .collect();
+
+        for package in packages.iter() {
+            if let Err(e) = self.send_msg(&client_id, package) {
+                eprintln!("Couldn't send the history to {}: {:?}", client_id, e);
+                return Err(ClientInputError::InternalServerError);
+            }
+        }
+        Ok(packages.len())
+    }
+
+    /// Returns `PermissionDenied` if the client isn't an operator
+    fn require_operator(&self, client_id: lnpkg::ClientId) -> Result<(), ClientInputError> {
+        match self.clients.get(&client_id) {
+            Some(client) if client.is_operator => Ok(()),
+            Some(_) => {
+                println!("Client {} isn't an operator", client_id);
+                Err(ClientInputError::PermissionDenied)
+            }
+            None => Err(ClientInputError::UnknownUser),
+        }
+    }
+
+    /// Parses the id of the client targeted by a moderation command
+    fn moderation_target(
+        &self,
+        argument: Option<&String>,
+    ) -> Result<lnpkg::ClientId, ClientInputError> {
+        let target_id: lnpkg::ClientId = argument
+            .and_then(|a| a.parse().ok())
+            .ok_or(ClientInputError::NonValidCommandUsage)?;
+        if !self.clients.contains_key(&target_id) {
+            return Err(ClientInputError::UnknownUser);
+        }
+        Ok(target_id)
+    }
+
+    /// Broadcasts the `event_moderation` package to every client
+    fn notify_moderation(
+        &mut self,
+        action: &str,
+        target_id: Option<lnpkg::ClientId>,
+        target: String,
+        operator_id: lnpkg::ClientId,
+        duration: Option<Duration>,
+    ) {
+        println!(
+            "Operator {} used {} against {} ({:?})",
+            operator_id, action, target, target_id
+        );
+        let template = msg_templates::server::event_moderation(
+            action,
+            target_id,
+            target,
+            operator_id,
+            duration.map(|d| d.as_secs()),
+        );
+        self.broadcast_msg(&template);
+    }
+
+    /// Disconnects the client specified, notifying the rest of clients
+    pub fn kick(
+        &mut self,
+        operator_id: lnpkg::ClientId,
+        target_id: lnpkg::ClientId,
+    ) -> Result<(), ClientInputError> {
+        let target = self
+            .clients
+            .get(&target_id)
+            .ok_or(ClientInputError::UnknownUser)?
+            .name
+            .clone();
+        self.notify_moderation("kick", Some(target_id), target, operator_id, None);
+        self.disconnect_and_notify(target_id)
+    }
+
+    /// Address the client specified is connected from
+    pub fn client_address(
+        &self,
+        client_id: lnpkg::ClientId,
+    ) -> Result<net::IpAddr, ClientInputError> {
+        let client = self
+            .clients
+            .get(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        match client.stream.peer_addr() {
+            Ok(addr) => Ok(addr.ip()),
+            Err(e) => {
+                eprintln!("Couldn't get the address of client {}: {:?}", client_id, e);
+                Err(ClientInputError::InternalServerError)
+            }
+        }
+    }
+
+    /// Doesn't allow more connections from the range for the duration specified (*or
+    /// until it's unbanned, if there is no duration*), and kicks the clients connected
+    /// from it (*except the operator*). `target_id` is the client whose address is being
+    /// banned (*if any*).
+    pub fn ban(
+        &mut self,
+        operator_id: lnpkg::ClientId,
+        range: IpRange,
+        target_id: Option<lnpkg::ClientId>,
+        duration: Option<Duration>,
+    ) -> Result<(), ClientInputError> {
+        match self.access.ban(range, duration) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Err(ClientInputError::NonValidCommandUsage)
+            }
+            Err(e) => {
+                eprintln!("Couldn't store the ban of {}: {:?}", range, e);
+                return Err(ClientInputError::InternalServerError);
+            }
+        }
+        self.notify_moderation("ban", target_id, range.to_string(), operator_id, duration);
+
+        let banned: Vec<lnpkg::ClientId> = self
+            .clients
+            .iter()
+            .filter(|(id, client)| {
+                **id != operator_id
+                    && client
+                        .stream
+                        .peer_addr()
+                        .is_ok_and(|addr| range.contains(&addr.ip()))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in banned {
+            let _ = self.disconnect_and_notify(id);
+        }
+        Ok(())
+    }
+
+    /// Allows connections from the range specified again
+    pub fn unban(
+        &mut self,
+        operator_id: lnpkg::ClientId,
+        range: IpRange,
+    ) -> Result<(), ClientInputError> {
+        match self.access.unban(&range) {
+            Ok(true) => (),
+            Ok(false) => return Err(ClientInputError::ResourceNotAvailable),
+            Err(e) => {
+                eprintln!("Couldn't store the unban of {}: {:?}", range, e);
+                return Err(ClientInputError::InternalServerError);
+            }
+        }
+        self.notify_moderation("unban", None, range.to_string(), operator_id, None);
+        Ok(())
+    }
+
+    /// Doesn't let the client specified send messages for the duration specified (*or
+    /// until it's unmuted, if there is no duration*)
+    pub fn mute(
+        &mut self,
+        operator_id: lnpkg::ClientId,
+        target_id: lnpkg::ClientId,
+        duration: Option<Duration>,
+    ) -> Result<(), ClientInputError> {
+        let client = self
+            .clients
+            .get_mut(&target_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        client.mute = Some(match duration {
+            Some(duration) => match Instant::now().checked_add(duration) {
+                Some(until) => Mute::Until(until),
+                None => return Err(ClientInputError::NonValidCommandUsage),
+            },
+            None => Mute::Indefinite,
+        });
+        let target = client.name.clone();
+        self.notify_moderation("mute", Some(target_id), target, operator_id, duration);
+        Ok(())
+    }
+
+    /// Lets a muted client send messages again
+    pub fn unmute(
+        &mut self,
+        operator_id: lnpkg::ClientId,
+        target_id: lnpkg::ClientId,
+    ) -> Result<(), ClientInputError> {
+        let client = self
+            .clients
+            .get_mut(&target_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        if client.mute.take().is_none() {
+            return Err(ClientInputError::ResourceNotAvailable);
+        }
+        let target = client.name.clone();
+        self.notify_moderation("unmute", Some(target_id), target, operator_id, None);
+        Ok(())
+    }
+
+    /// Account the client is logged in with
+    fn account_of(&self, client_id: lnpkg::ClientId) -> Result<AccountId, ClientInputError> {
+        self.clients
+            .get(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?
+            .account_id
+            .ok_or(ClientInputError::NotLoggedIn)
+    }
+
+    /// Adds the client to the room (*creating it if nobody was in it*), and notifies the
+    /// members of the room
+    pub fn join_room(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        room: String,
+    ) -> Result<(), ClientInputError> {
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        if !client.rooms.insert(room.clone()) {
+            return Ok(()); // Already a member
+        }
+        let template =
+            msg_templates::server::event_room_joined(room.clone(), client_id, client.name.clone());
+        self.broadcast_to_room(&room, &template);
+        Ok(())
+    }
+
+    /// Removes the client from the room, notifying the members of the room (*including
+    /// the client*)
+    pub fn part_room(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        room: String,
+    ) -> Result<(), ClientInputError> {
+        let client = self
+            .clients
+            .get(&client_id)
+            .ok_or(ClientInputError::UnknownUser)?;
+        if !client.rooms.contains(&room) {
+            return Err(ClientInputError::ResourceNotAvailable);
+        }
+        let template =
+            msg_templates::server::event_room_parted(room.clone(), client_id, client.name.clone());
+        self.broadcast_to_room(&room, &template);
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.rooms.remove(&room);
+        }
+        Ok(())
+    }
+
+    /// Rooms that have at least one member, along with their amount of members, sorted by name
+    pub fn list_rooms(&self) -> Vec<(String, usize)> {
+        let mut rooms: HashMap<&str, usize> = HashMap::new();
+        for client in self.clients.values() {
+            for room in client.rooms.iter() {
+                *rooms.entry(room.as_str()).or_insert(0) += 1;
+            }
+        }
+        let mut rooms: Vec<(String, usize)> = rooms
+            .into_iter()
+            .map(|(name, members)| (name.to_string(), members))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Summary of every client connected, sorted by their id
+    pub fn list_clients(&self) -> Vec<msg_templates::server::ClientSummary> {
+        let mut summaries: Vec<msg_templates::server::ClientSummary> = self
+            .clients
+            .iter()
+            .map(|(id, client)| msg_templates::server::ClientSummary {
+                id: *id,
+                name: client.name.clone(),
+                connected_since: client
+                    .connected_since
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+                idle: Some(client.last_activity.elapsed().as_secs()),
+            })
+            .collect();
+        summaries.sort_by_key(|c| c.id);
+        summaries
+    }
+
+    /// Names the client that just connected after the nickname it requested, or after
+    /// a guest name if it can't take it (*see `check_nick` and `guest_name`*). Returns
+    /// the name given, `None` if no name is available.
+    pub fn assign_name(
+        &mut self,
+        client_id: lnpkg::ClientId,
+        preferred: Option<&str>,
+    ) -> Option<String> {
+        let name = match preferred {
+            Some(nick) if self.nick_available(client_id, nick) => nick.to_string(),
+            Some(nick) => {
+                println!("Client {} can't take the nickname {}", client_id, nick);
+                self.guest_name(client_id)?
+            }
+            None => self.guest_name(client_id)?,
+        };
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.name = name.clone();
+        }
+        Some(name)
+    }
+
+    /// Name for a client that didn't pick one, `guest-<id>` (*followed by a counter if
+    /// somebody has already taken it*). Only the first `MAX_GUEST_NAMES` are tried, since
+    /// the nickname rules might not allow any of them.
+    pub fn guest_name(&self, client_id: lnpkg::ClientId) -> Option<String> {
+        let base = format!("guest-{}", client_id);
+        (1..=MAX_GUEST_NAMES)
+            .map(|counter| match counter {
+                1 => base.clone(),
+                _ => format!("{}-{}", base, counter),
+            })
+            .find(|name| self.nick_available(client_id, name))
+    }
+
+    /// Whether the client can take the nickname without giving any secret
+    fn nick_available(&self, client_id: lnpkg::ClientId, nick: &str) -> bool {
+        let claimed = !matches!(self.storage.nick_secret(&nick_key(nick)), Ok(None));
+        !claimed && self.check_nick(client_id, nick).is_ok()
+    }
+
+    /// Checks that the client specified can take the nickname: it has to follow the
+    /// `nick_rules`, and no other client can be using it.
+    pub fn check_nick(
+        &self,
+        client_id: lnpkg::ClientId,
+        nick: &str,
+    ) -> Result<(), ClientInputError> {
+        self.nick_rules.check(nick)?;
+        let taken = self
+            .clients
+            .iter()
+            .any(|(id, client)| *id != client_id && same_nick(&client.name, nick));
+        if taken {
+            return Err(ClientInputError::NicknameTaken);
+        }
+        Ok(())
+    }
+
+    /// Changes the name of the client specified (*see `check_nick`*), and broadcasts the
+    /// `event_client_renamed` package.
     pub fn change_name(
         &mut self,
         target_id: lnpkg::ClientId,
@@ -250,75 +1854,289 @@ impl Server {
     ) -> Result<(), ClientInputError> {
         if !self.clients.contains_key(&target_id) {
             return Err(ClientInputError::UnknownUser);
-        } else {
-            let cl_obj = self.clients.get_mut(&target_id).unwrap();
-            cl_obj.name = new_name;
         }
+        self.check_nick(target_id, &new_name)?;
+
+        let cl_obj = self.clients.get_mut(&target_id).unwrap();
+        let old_name = std::mem::replace(&mut cl_obj.name, new_name.clone());
+        println!(
+            "Client {} renamed from {} to {}",
+            target_id, old_name, new_name
+        );
+        self.broadcast_msg(&msg_templates::server::event_client_renamed(
+            target_id, old_name, new_name,
+        ));
         Ok(())
     }
 }
 
+/// Compares the strings in a time that doesn't depend on where they differ, so the
+/// time taken doesn't tell how much of a password was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Parses the optional duration (*in seconds*) given to a moderation command
+fn parse_duration(argument: Option<&String>) -> Result<Option<Duration>, ClientInputError> {
+    match argument {
+        Some(secs) => match secs.parse() {
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(ClientInputError::NonValidCommandUsage),
+        },
+        None => Ok(None),
+    }
+}
+
 /// Handles the incoming events from the client
 pub fn handle_client(server: Arc<Mutex<Server>>, mut client_stream: net::TcpStream) {
-    // Define the user
-    let client_name = String::from("Generic user name");
-    let client_id = server.lock().unwrap().add_client(Client {
-        name: client_name.clone(),
-        stream: client_stream.try_clone().unwrap(),
-    });
-    println!("Thread started for client {}", client_id);
-    // Send identity msg
-    client_stream
-        .write(
-            msg_templates::server::identity(client_id, client_name.clone())
-                .as_bytes()
-                .as_slice(),
-        )
-        .unwrap();
+    let server_guard = server.lock().unwrap();
+    // Checked before anything else, so banned addresses don't even learn if the server is full
+    if let Ok(address) = client_stream.peer_addr() {
+        if !server_guard.access.is_allowed(&address.ip()) {
+            println!("Connection refused, {} is not allowed.", address.ip());
+            let template = msg_templates::server::error(
+                msg_templates::error_code::BANNED,
+                "You are not allowed to connect to this server.".to_string(),
+            );
+            // Sent with the default codec, since the client hasn't said which one it speaks
+            let _ = write_package(&mut client_stream, &codec::LNPKG, &template);
+            return;
+        }
+    }
+    let handshake_timeout = server_guard.heartbeat.timeout;
+    let max_message_size = server_guard.max_message_size;
+    std::mem::drop(server_guard);
+
+    // Handshake (*the lock isn't held meanwhile, the client might take its time*)
+    let mut reader =
+        framing::FrameReader::new(client_stream.try_clone().unwrap(), max_message_size);
+    let _ = client_stream.set_read_timeout(Some(handshake_timeout));
+    // The codec is told by the first byte of the hello (*see `codec::detect`*)
+    let (codec, hello) = match reader.peek_non_whitespace() {
+        Ok(Some(byte)) => {
+            let codec = codec::detect(byte);
+            reader.set_framing(codec.framing());
+            (codec, read_hello(&mut reader, codec))
+        }
+        Ok(None) | Err(_) => (&codec::LNPKG as &dyn Codec, None),
+    };
+    let (version, capabilities, preferred_nick) = match hello {
+        Some(Ok(ClientMessage::Hello {
+            version,
+            capabilities,
+            nick,
+            ..
+        })) => (Some(version), capabilities, nick),
+        // A hello without a valid version can't be compatible
+        Some(Err(ParseError::MissingKey("version")))
+        | Some(Err(ParseError::WrongType { key: "version", .. })) => (None, vec![], None),
+        _ => {
+            println!("Connection refused, the client didn't send a hello.");
+            let template = msg_templates::server::error(
+                msg_templates::error_code::HANDSHAKE_REQUIRED,
+                "The connection has to start with a hello package.".to_string(),
+            );
+            let _ = write_package(&mut client_stream, codec, &template);
+            return;
+        }
+    };
+    let _ = client_stream.set_read_timeout(None);
+    if !version.is_some_and(shared::is_compatible) {
+        println!(
+            "Connection refused, incompatible protocol version {:?}.",
+            version
+        );
+        let template = msg_templates::server::error(
+            msg_templates::error_code::INCOMPATIBLE_VERSION,
+            format!(
+                "The server speaks versions {} to {} of the protocol.",
+                shared::MIN_PROTOCOL_VERSION,
+                shared::PROTOCOL_VERSION
+            ),
+        );
+        let _ = write_package(&mut client_stream, codec, &template);
+        return;
+    }
+    // Only the capabilities known by both sides are used
+    let capabilities: Vec<String> = capabilities
+        .into_iter()
+        .filter(|c| capability::ALL.contains(&c.as_str()))
+        .collect();
+
+    let mut server_guard = server.lock().unwrap();
+    if server_guard.is_full() {
+        println!("Connection refused, the server is full.");
+        let template = msg_templates::server::error(
+            msg_templates::error_code::SERVER_FULL,
+            "The server is full.".to_string(),
+        );
+        let _ = write_package(&mut client_stream, codec, &template);
+        return;
+    }
+    let queue_size = server_guard.outbound.queue_size;
+    let client_id = server_guard.add_client(Client::new(
+        String::new(),
+        client_stream.try_clone().unwrap(),
+        queue_size,
+    ));
+    let client_name = match server_guard.assign_name(client_id, preferred_nick.as_deref()) {
+        Some(name) => name,
+        None => {
+            println!(
+                "Connection refused, no name is available for client {}.",
+                client_id
+            );
+            let template = msg_templates::server::error(
+                msg_templates::error_code::INVALID_NICKNAME,
+                "No guest name is available, try again with another nickname.".to_string(),
+            );
+            let _ = write_package(&mut client_stream, codec, &template);
+            let _ = server_guard.disconnect_client(client_id);
+            return;
+        }
+    };
+    println!("Thread started for client {} ({})", client_id, client_name);
+    if let Some(client) = server_guard.clients.get_mut(&client_id) {
+        client.capabilities = capabilities.clone();
+        client.codec = codec;
+    }
+    // Send welcome and identity msgs (*before releasing the lock, so they're the first
+    // packages queued*)
+    let welcome = shared::welcome(
+        env!("CARGO_PKG_NAME").to_string(),
+        env!("CARGO_PKG_VERSION").to_string(),
+        capabilities.clone(),
+        codec.name().to_string(),
+    );
+    let identity = msg_templates::server::identity(client_id, client_name.clone());
+    // Only fails if the outbound queue can't hold them (*see `HANDSHAKE_PACKAGES`*)
+    if let Err(e) = [welcome, identity]
+        .iter()
+        .try_for_each(|pkg| server_guard.send_msg(&client_id, pkg))
+    {
+        eprintln!(
+            "Couldn't send the handshake to client {}: {:?}",
+            client_id, e
+        );
+        let _ = server_guard.disconnect_client(client_id);
+        return;
+    }
+    if capabilities.iter().any(|c| c == capability::HISTORY) {
+        let history_replay = server_guard.history_replay;
+        let _ = server_guard.send_history(client_id, history_replay, None);
+    }
+    if let Some(motd) = server_guard.motd.clone() {
+        let _ = server_guard.send_msg(&client_id, &msg_templates::server::motd(motd));
+    }
+    std::mem::drop(server_guard);
     // Send event msg
     server
         .lock()
         .unwrap()
-        .broadcast_msg(
-            msg_templates::server::event_client_connected(client_id, client_name.clone())
-                .as_bytes()
-                .as_slice(),
-        )
-        .unwrap();
+        .broadcast_msg(&msg_templates::server::event_client_connected(
+            client_id,
+            client_name.clone(),
+        ));
 
     // Mainloop
-    let mut buffer = vec![0; crate::BUFF_SIZE];
+    // Amount of times that each error has occurred, used for the `ErrorPolicy::FatalAfter`
+    let mut strikes: HashMap<ClientInputError, usize> = HashMap::new();
     loop {
-        if client_stream.read(&mut buffer).unwrap() == 0 {
-            // Empty packet (Connection closed)
-            break;
-        } else {
-            let mut server_guard = server.lock().unwrap();
-
-            // Remove NUL characters
-            buffer = buffer.into_iter().filter(|c| *c != 0).collect();
-            let result = server_guard.handle_client_input(client_id, &buffer);
-            std::mem::drop(server_guard); // Avoid double locking
-            match result {
-                Ok(_) => (),
-                Err(e) => {
-                    // Act according the type of error (do not disconnect in certain cases)
-                    println!("Error when handling message: {:?}", e);
-                    let mut server_guard = server.lock().unwrap();
-                    server_guard.disconnect_client(client_id).unwrap();
-                    server_guard
-                        .broadcast_msg(
-                            msg_templates::server::event_client_left(client_id, client_name)
-                                .as_bytes()
-                                .as_slice(),
-                        )
-                        .unwrap();
-                    println!("Client ({}) disconnected from the server.", client_id);
-                    break;
+        let frame = match reader.read_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break, // Connection closed
+            Err(e) => {
+                eprintln!("Error reading from client {}: {:?}", client_id, e);
+                break;
+            }
+        };
+
+        let mut server_guard = server.lock().unwrap();
+        let mut result = server_guard.handle_client_input(client_id, &frame);
+        std::mem::drop(server_guard); // Avoid double locking
+
+        // Hashing passwords is slow, so it's done without holding the lock
+        if let Ok(InputOutcome::Password(command)) = result {
+            let done = command.run();
+            result = server
+                .lock()
+                .unwrap()
+                .complete_password_command(client_id, done);
+        }
+
+        // Malformed packages are only fatal while they're consecutive, so a client isn't
+        // disconnected for a few mistakes spread over a long session
+        if !matches!(&result, Err(e) if e.is_malformed_package()) {
+            strikes.retain(|e, _| !e.is_malformed_package());
+        }
+        let e = match result {
+            // Already reported through the result package of the command
+            Ok(InputOutcome::CommandFailed(e)) => e,
+            Ok(_) => continue,
+            Err(e) => {
+                // Act according the type of error (do not disconnect in certain cases)
+                println!("Error when handling message: {:?}", e);
+                let mut server_guard = server.lock().unwrap();
+                if let Err(send_error) = server_guard.send_error(&client_id, &e) {
+                    eprintln!(
+                        "Couldn't send the error to client {}: {:?}",
+                        client_id, send_error
+                    );
                 }
-            };
-            buffer = vec![0; crate::BUFF_SIZE];
+                e
+            }
+        };
+
+        let count = strikes.entry(e).or_insert(0);
+        *count += 1;
+        match e.policy() {
+            ErrorPolicy::FatalAfter(max) if *count >= max => break,
+            _ => continue,
         }
     }
+
+    // The client might have already been removed (*eg. by the heartbeat checks*)
+    let _ = server.lock().unwrap().disconnect_and_notify(client_id);
     eprintln!("Killed thread for client {}.", client_id);
 }
+
+/// Writes the package straight to the stream, bypassing the outbound queue (*only used
+/// before the client has been added to the server*)
+fn write_package(
+    stream: &mut net::TcpStream,
+    codec: &dyn Codec,
+    pkg: &lnpkg::LnPkg,
+) -> io::Result<()> {
+    stream.write_all(&codec.encode_frame(pkg)?)
+}
+
+/// Reads the first package of the connection (*`None` if the connection is closed
+/// before it arrives, or if it can't be decoded*)
+fn read_hello(
+    reader: &mut framing::FrameReader<net::TcpStream>,
+    codec: &dyn Codec,
+) -> Option<Result<ClientMessage, ParseError>> {
+    let frame = match reader.read_frame() {
+        Ok(Some(frame)) => frame,
+        Ok(None) | Err(_) => return None,
+    };
+    Some(ClientMessage::try_from(codec.decode(&frame).ok()?))
+}
+
+/// Periodically checks the liveness of the clients connected (*see `Server::check_heartbeats`*),
+/// and the state of their outbound queues (*see `Server::check_slow_consumers`*),
+/// until `stop` is set.
+pub fn run_heartbeat(server: Arc<Mutex<Server>>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::SeqCst) {
+        let interval = server.lock().unwrap().heartbeat.interval;
+        // Check several times per interval, so that timeouts are noticed soon enough
+        thread::sleep(interval / 4);
+        let mut server_guard = server.lock().unwrap();
+        server_guard.check_heartbeats();
+        server_guard.check_slow_consumers();
+    }
+}
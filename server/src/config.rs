@@ -0,0 +1,248 @@
+use crate::access::IpRange;
+use crate::comm_elements::HANDSHAKE_PACKAGES;
+use crate::nicks::NickRules;
+use serde::Deserialize;
+use std::{fmt, fs, time::Duration};
+
+const USAGE: &str = "Usage: socks [OPTIONS]
+
+Options:
+  --config <path>                 TOML file to read the configuration from
+  --bind <addr>                   Address the server listens on
+  --max-clients <n>               Maximum amount of clients connected at once (0 = no limit)
+  --max-message-size <bytes>      Maximum size of a single package sent by a client
+  --motd <text>                   Message of the day sent to every client that connects
+  --heartbeat-interval <secs>     Idle time before a client gets pinged
+  --heartbeat-timeout <secs>      Idle time before a client gets disconnected
+  --queue-size <n>                Maximum amount of packages waiting to be sent to each client
+  --slow-consumer-timeout <secs>  Time an outbound queue can stay full before its client gets
+                                  disconnected (0 = just drop the packages that don't fit)
+  --accounts-file <path>          File in which the accounts registered are stored
+  --oper-password <password>      Password of the `oper` command (operators disabled if not set)
+  --history-size <n>              Amount of messages kept in the history (0 = no history)
+  --history-replay <n>            Amount of messages of the history sent to each client
+                                  that connects
+  --database <path>               SQLite database in which the history, the client ids and the
+                                  settings of the accounts are stored (kept in memory if not set)
+  --bans-file <path>              File in which the addresses banned are stored
+  --allow <range>                 Address or CIDR range allowed to connect, can be repeated
+                                  (every address is allowed if none is given)
+  --nick-min-length <n>           Minimum length of the nicknames
+  --nick-max-length <n>           Maximum length of the nicknames
+  --nick-symbols <chars>          Characters allowed in the nicknames besides letters and digits
+  --reserved-nick <nick>          Nickname that nobody can take, can be repeated
+  --help                          Shows this message
+
+Options given in the command line take precedence over the ones in the config file.";
+
+/// Configuration of the server, read from a TOML file (*see `socks.example.toml`*)
+/// and the command line arguments.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    /// `0` means that there is no limit
+    pub max_clients: usize,
+    pub max_message_size: usize,
+    pub motd: Option<String>,
+    /// In seconds
+    pub heartbeat_interval: u64,
+    /// In seconds
+    pub heartbeat_timeout: u64,
+    pub queue_size: usize,
+    /// In seconds, `0` means that the packages that don't fit are dropped
+    pub slow_consumer_timeout: u64,
+    pub accounts_file: String,
+    /// Password that grants the operator role through the `oper` command (*nobody
+    /// can become an operator if it's not set*)
+    pub oper_password: Option<String>,
+    /// `0` disables the history
+    pub history_size: usize,
+    pub history_replay: usize,
+    /// Path of the SQLite database (*the state is kept in memory if it's not set*)
+    pub database: Option<String>,
+    pub bans_file: String,
+    /// Addresses allowed to connect (*every address is allowed if it's empty*)
+    pub allow: Vec<IpRange>,
+    pub nick_min_length: usize,
+    pub nick_max_length: usize,
+    /// Characters allowed in the nicknames besides letters and digits
+    pub nick_symbols: String,
+    /// Nicknames that nobody can take (*compared without case*)
+    pub reserved_nicks: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+            max_clients: 0,
+            max_message_size: 64 * 1024,
+            motd: None,
+            heartbeat_interval: 30,
+            heartbeat_timeout: 90,
+            queue_size: 256,
+            slow_consumer_timeout: 10,
+            accounts_file: "accounts.txt".to_string(),
+            oper_password: None,
+            history_size: 100,
+            history_replay: 20,
+            database: None,
+            bans_file: "bans.txt".to_string(),
+            allow: vec![],
+            nick_min_length: 1,
+            nick_max_length: 32,
+            nick_symbols: "_-.".to_string(),
+            reserved_nicks: vec!["server".to_string(), "admin".to_string()],
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Errors that can occur when reading the configuration
+pub enum ConfigError {
+    /// `--help` has been requested, the usage should be shown
+    Help,
+    /// The config file couldn't be read
+    Io(String, std::io::Error),
+    /// The config file isn't valid TOML, or contains unknown options
+    Toml(String, toml::de::Error),
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    /// The options can't be used together, or with the value given (*see `Config::validate`*)
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Help => write!(f, "{}", USAGE),
+            Self::Io(path, e) => write!(f, "Cannot read config file '{}': {}", path, e),
+            Self::Toml(path, e) => write!(f, "Non valid config file '{}': {}", path, e),
+            Self::UnknownFlag(flag) => write!(f, "Unknown option '{}'.\n\n{}", flag, USAGE),
+            Self::MissingValue(flag) => write!(f, "The option '{}' requires a value.", flag),
+            Self::InvalidValue(flag, value) => {
+                write!(f, "Non valid value '{}' for the option '{}'.", value, flag)
+            }
+            Self::Invalid(reason) => write!(f, "Non valid configuration: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file, options that aren't present keep
+    /// their default value. The configuration read is validated (*see `validate`*).
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let config = Self::read_file(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Same as `from_file`, but without validating the configuration, so the flags can
+    /// still change it
+    fn read_file(path: &str) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Toml(path.to_string(), e))
+    }
+
+    /// Builds the configuration from the command line arguments (*without the name of
+    /// the executable*), loading the config file first if `--config` is specified.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ConfigError> {
+        let mut flags: Vec<(String, String)> = vec![];
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                return Err(ConfigError::Help);
+            }
+            if !flag.starts_with("--") {
+                return Err(ConfigError::UnknownFlag(flag));
+            }
+            match args.next() {
+                Some(value) => flags.push((flag, value)),
+                None => return Err(ConfigError::MissingValue(flag)),
+            }
+        }
+
+        let mut config = match flags.iter().rev().find(|(flag, _)| flag == "--config") {
+            Some((_, path)) => Self::read_file(path)?,
+            None => Self::default(),
+        };
+        for (flag, value) in flags {
+            config.set(&flag, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the options that the server can't work with
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+        if self.heartbeat_interval == 0 {
+            return invalid("the heartbeat interval can't be 0.");
+        }
+        if self.heartbeat_timeout < self.heartbeat_interval {
+            return invalid("the heartbeat timeout can't be shorter than its interval.");
+        }
+        if self.queue_size < HANDSHAKE_PACKAGES {
+            return Err(ConfigError::Invalid(format!(
+                "the queue size must be at least {}.",
+                HANDSHAKE_PACKAGES
+            )));
+        }
+        if self.nick_min_length > self.nick_max_length {
+            return invalid("the minimum length of the nicknames is above the maximum.");
+        }
+        // Clients that don't pick a nickname are named `guest-<id>`
+        let rules = NickRules::from_config(self);
+        if ["guest-1", &format!("guest-{}", u32::MAX)]
+            .iter()
+            .any(|guest| rules.check(guest).is_err())
+        {
+            return invalid("the nickname rules don't allow the guest names (`guest-<id>`).");
+        }
+        Ok(())
+    }
+
+    /// Sets the option that corresponds to the flag given
+    fn set(&mut self, flag: &str, value: String) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+            value
+                .parse()
+                .map_err(|_| ConfigError::InvalidValue(flag.to_string(), value.to_string()))
+        }
+
+        match flag {
+            "--config" => (), // Already loaded
+            "--bind" => self.bind = value,
+            "--max-clients" => self.max_clients = parse(flag, &value)?,
+            "--max-message-size" => self.max_message_size = parse(flag, &value)?,
+            "--motd" => self.motd = Some(value),
+            "--heartbeat-interval" => self.heartbeat_interval = parse(flag, &value)?,
+            "--heartbeat-timeout" => self.heartbeat_timeout = parse(flag, &value)?,
+            "--queue-size" => self.queue_size = parse(flag, &value)?,
+            "--slow-consumer-timeout" => self.slow_consumer_timeout = parse(flag, &value)?,
+            "--accounts-file" => self.accounts_file = value,
+            "--oper-password" => self.oper_password = Some(value),
+            "--history-size" => self.history_size = parse(flag, &value)?,
+            "--history-replay" => self.history_replay = parse(flag, &value)?,
+            "--database" => self.database = Some(value),
+            "--bans-file" => self.bans_file = value,
+            "--allow" => self.allow.push(parse(flag, &value)?),
+            "--nick-min-length" => self.nick_min_length = parse(flag, &value)?,
+            "--nick-max-length" => self.nick_max_length = parse(flag, &value)?,
+            "--nick-symbols" => self.nick_symbols = value,
+            "--reserved-nick" => self.reserved_nicks.push(value),
+            _ => return Err(ConfigError::UnknownFlag(flag.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout)
+    }
+}
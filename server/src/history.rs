@@ -0,0 +1,121 @@
+use msg_templates::Lnp;
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Message sent to a room, kept in the history of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Identifier of the message, increases with every message sent
+    pub id: u64,
+    /// Moment in which the message was sent (*in seconds since the UNIX epoch*)
+    pub timestamp: u64,
+    pub author_id: lnpkg::ClientId,
+    pub room: String,
+    pub msg: String,
+}
+
+impl HistoryEntry {
+    /// The `server::msg` package of the message
+    pub fn to_package(&self) -> Lnp {
+        msg_templates::server::msg(
+            self.author_id,
+            self.msg.clone(),
+            self.room.clone(),
+            self.id,
+            self.timestamp,
+        )
+    }
+}
+
+/// Last messages sent to the rooms of the server, the oldest ones are forgotten once
+/// the capacity is reached.
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+    last_id: u64,
+}
+
+impl History {
+    /// History that keeps up to `capacity` messages (*`0` disables it*)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            // Grows as the messages are sent, the capacity might be huge
+            entries: VecDeque::new(),
+            capacity,
+            last_id: 0,
+        }
+    }
+
+    /// History that keeps up to `capacity` messages, starting with the entries given
+    /// (*from the oldest to the newest*). The ids of the new messages start after `last_id`.
+    pub fn restore(capacity: usize, entries: Vec<HistoryEntry>, last_id: u64) -> Self {
+        let mut history = Self::new(capacity);
+        let skip = entries.len().saturating_sub(capacity);
+        history.entries.extend(entries.into_iter().skip(skip));
+        history.last_id = last_id;
+        history
+    }
+
+    /// Stores the message, returning the entry created for it. Even if the history is
+    /// disabled, the entry gets an id and a timestamp.
+    pub fn push(&mut self, author_id: lnpkg::ClientId, room: String, msg: String) -> HistoryEntry {
+        self.last_id += 1;
+        let entry = HistoryEntry {
+            id: self.last_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            author_id,
+            room,
+            msg,
+        };
+
+        if self.capacity == 0 {
+            return entry;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry.clone());
+        entry
+    }
+
+    /// Up to `n` of the last messages that match the filter, sent before the message
+    /// `before` (*if specified*), from the oldest to the newest.
+    pub fn last<F: Fn(&HistoryEntry) -> bool>(
+        &self,
+        n: usize,
+        before: Option<u64>,
+        filter: F,
+    ) -> Vec<&HistoryEntry> {
+        let mut entries: Vec<&HistoryEntry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| match before {
+                Some(before) => entry.id < before,
+                None => true,
+            })
+            .filter(|entry| filter(entry))
+            .take(n)
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -0,0 +1,195 @@
+//! `rust-socks` server, it can be started from the `socks` binary, or embedded in
+//! other programs (*and tests*) through `ServerBuilder`:
+//!
+//! ```no_run
+//! let server = socks::ServerBuilder::new().bind("127.0.0.1:0").build().unwrap();
+//! println!("Listening on {}", server.local_addr());
+//! server.run().unwrap();
+//! ```
+use comm_elements::*;
+use std::{
+    io, net,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+// Modules
+pub mod access;
+pub mod accounts;
+pub mod comm_elements;
+pub mod config;
+pub mod history;
+pub mod nicks;
+pub mod storage;
+
+/// Builds a `SocksServer`, the options that aren't specified keep the values of
+/// `config::Config::default()`, and accounts are kept in memory.
+pub struct ServerBuilder {
+    config: config::Config,
+    accounts: Option<Box<dyn accounts::AccountStore>>,
+    access: Option<access::AccessList>,
+    storage: Option<Box<dyn storage::Storage>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::from_config(config::Config::default())
+    }
+
+    pub fn from_config(config: config::Config) -> Self {
+        Self {
+            config,
+            accounts: None,
+            access: None,
+            storage: None,
+        }
+    }
+
+    /// Address the server listens on, use port `0` to let the OS pick a free one
+    /// (*the port assigned can be checked with `SocksServer::local_addr`*)
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind = addr.into();
+        self
+    }
+
+    /// Storage used for the accounts registered
+    pub fn accounts(mut self, store: Box<dyn accounts::AccountStore>) -> Self {
+        self.accounts = Some(store);
+        self
+    }
+
+    /// Addresses allowed to connect and the ones banned (*by default, every address
+    /// in `Config::allow` is allowed, and bans are kept in memory*)
+    pub fn access(mut self, access: access::AccessList) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    /// Storage of the state that survives restarts (*by default, it's kept in memory*)
+    pub fn storage(mut self, storage: Box<dyn storage::Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Binds the listener, the server doesn't accept clients until it's run. Fails
+    /// if the configuration isn't valid (*see `Config::validate`*).
+    pub fn build(self) -> io::Result<SocksServer> {
+        self.config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let listener = net::TcpListener::bind(&self.config.bind)?;
+        let local_addr = listener.local_addr()?;
+        let mut server = Server::from_config(&self.config);
+        if let Some(accounts) = self.accounts {
+            server.accounts = accounts;
+        }
+        if let Some(access) = self.access {
+            server.access = access;
+        }
+        if let Some(storage) = self.storage {
+            server.set_storage(storage).map_err(io::Error::other)?;
+        }
+
+        Ok(SocksServer {
+            listener,
+            handle: ServerHandle {
+                server: Arc::new(Mutex::new(server)),
+                local_addr,
+                stop: Arc::new(AtomicBool::new(false)),
+            },
+        })
+    }
+}
+
+/// Server bound to an address, ready to accept clients
+pub struct SocksServer {
+    listener: net::TcpListener,
+    handle: ServerHandle,
+}
+
+impl SocksServer {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.handle.local_addr
+    }
+
+    /// Handle that can be used to stop the server from another thread
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Accepts clients until `ServerHandle::shutdown` is called (*blocks the thread*)
+    pub fn run(self) -> io::Result<()> {
+        let heartbeat_server = Arc::clone(&self.handle.server);
+        let heartbeat_stop = Arc::clone(&self.handle.stop);
+        thread::spawn(move || run_heartbeat(heartbeat_server, heartbeat_stop));
+
+        for stream in self.listener.incoming() {
+            if self.handle.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let second_server = Arc::clone(&self.handle.server);
+                    thread::spawn(move || handle_client(second_server, stream));
+                }
+                Err(e) => eprintln!("Error accepting connection: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the server in a new thread, returning its handle
+    pub fn spawn(self) -> ServerHandle {
+        let handle = self.handle();
+        thread::spawn(move || self.run());
+        handle
+    }
+}
+
+/// Handle to a `SocksServer`, it can be cloned and sent to other threads
+#[derive(Clone)]
+pub struct ServerHandle {
+    server: Arc<Mutex<Server>>,
+    local_addr: net::SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl ServerHandle {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// State of the server, shared with the threads handling the clients
+    pub fn server(&self) -> Arc<Mutex<Server>> {
+        Arc::clone(&self.server)
+    }
+
+    /// Stops accepting clients and disconnects the ones connected
+    pub fn shutdown(&self) {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return; // Already shut down
+        }
+        self.server.lock().unwrap().disconnect_all();
+
+        // Wake up the listener, which is blocked waiting for a connection
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            match addr {
+                net::SocketAddr::V4(_) => addr.set_ip(net::Ipv4Addr::LOCALHOST.into()),
+                net::SocketAddr::V6(_) => addr.set_ip(net::Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+        let _ = net::TcpStream::connect(addr);
+    }
+}
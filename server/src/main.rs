@@ -1,20 +1,35 @@
-use comm_elements::*;
-use std::net;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use socks::{access, accounts, config, storage, ServerBuilder};
+use std::{env, process};
 
-// Modules
-mod comm_elements;
-
-const SERVER_ADDR: &str = "127.0.0.1:8080";
-const BUFF_SIZE: usize = 1024;
 fn main() {
-    println!("Server started.");
-    let listener = net::TcpListener::bind(SERVER_ADDR).expect("Cannot bind socket.");
-    let mut server = Arc::new(Mutex::new(Server::default()));
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let second_server = Arc::clone(&mut server);
-        thread::spawn(move || handle_client(second_server, stream));
+    let config = match config::Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(config::ConfigError::Help) => {
+            println!("{}", config::ConfigError::Help);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let accounts = accounts::FileAccountStore::open(&config.accounts_file)
+        .expect("Cannot open the accounts file.");
+    let access = access::AccessList::open(&config.bans_file, config.allow.clone())
+        .expect("Cannot open the bans file.");
+    let storage = config
+        .database
+        .as_ref()
+        .map(|database| storage::SqliteStorage::open(database).expect("Cannot open the database."));
+
+    let mut builder = ServerBuilder::from_config(config)
+        .accounts(Box::new(accounts))
+        .access(access);
+    if let Some(storage) = storage {
+        builder = builder.storage(Box::new(storage));
     }
+    let server = builder.build().expect("Cannot start the server.");
+    println!("Server started on {}.", server.local_addr());
+    server.run().expect("Server stopped unexpectedly.");
 }
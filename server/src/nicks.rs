@@ -0,0 +1,73 @@
+use crate::config::Config;
+
+/// Rules that the nicknames chosen by the clients must follow
+#[derive(Debug, Clone, PartialEq)]
+pub struct NickRules {
+    /// Minimum amount of characters
+    pub min_length: usize,
+    /// Maximum amount of characters
+    pub max_length: usize,
+    /// Characters allowed besides letters and digits
+    pub symbols: String,
+    /// Nicknames that nobody can take (*compared without case*)
+    pub reserved: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+/// Rule broken by a nickname
+pub enum NickError {
+    TooShort,
+    TooLong,
+    /// The nickname contains the character specified, which isn't allowed
+    NonValidCharacter(char),
+    Reserved,
+}
+
+impl Default for NickRules {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+impl NickRules {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            min_length: config.nick_min_length,
+            max_length: config.nick_max_length,
+            symbols: config.nick_symbols.clone(),
+            reserved: config.reserved_nicks.clone(),
+        }
+    }
+
+    /// Checks that the nickname follows every rule
+    pub fn check(&self, nick: &str) -> Result<(), NickError> {
+        let length = nick.chars().count();
+        if length < self.min_length.max(1) {
+            return Err(NickError::TooShort);
+        }
+        if length > self.max_length {
+            return Err(NickError::TooLong);
+        }
+        if let Some(c) = nick
+            .chars()
+            .find(|c| !c.is_alphanumeric() && !self.symbols.contains(*c))
+        {
+            return Err(NickError::NonValidCharacter(c));
+        }
+        if self.reserved.iter().any(|r| same_nick(r, nick)) {
+            return Err(NickError::Reserved);
+        }
+        Ok(())
+    }
+}
+
+/// Whether both nicknames are the same, nicknames don't take case into account
+pub fn same_nick(a: &str, b: &str) -> bool {
+    nick_key(a) == nick_key(b)
+}
+
+/// Form of the nickname used to compare and store it (*so `Someone` and `someone` are
+/// the same nickname*)
+pub fn nick_key(nick: &str) -> String {
+    nick.to_lowercase()
+}
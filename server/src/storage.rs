@@ -0,0 +1,422 @@
+use crate::accounts::AccountId;
+use crate::history::HistoryEntry;
+use crate::nicks::nick_key;
+use rusqlite::{functions::FunctionFlags, params, OptionalExtension};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug)]
+/// Errors that can occur when reading or writing the state of the server
+pub enum StorageError {
+    /// Error returned by SQLite
+    Sqlite(rusqlite::Error),
+    /// The database has been created by a newer version of the server (*its schema
+    /// version is the one specified*)
+    UnknownVersion(usize),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            Self::UnknownVersion(version) => write!(
+                f,
+                "The database has the schema version {}, which is newer than the ones known ({}).",
+                version,
+                MIGRATIONS.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+/// Direct message sent to a nickname whose owner wasn't connected, waiting to be
+/// delivered the next time the owner takes the nickname
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfflineMessage {
+    /// Nickname the message is addressed to
+    pub recipient: String,
+    pub author_id: lnpkg::ClientId,
+    pub author_name: String,
+    /// Moment in which the message was sent (*in seconds since the UNIX epoch*)
+    pub timestamp: u64,
+    pub msg: String,
+}
+
+/// Storage of the state of the server that has to survive restarts
+pub trait Storage: Send {
+    /// Stores a message of the history
+    fn save_message(&mut self, entry: &HistoryEntry) -> StorageResult<()>;
+    /// Up to `limit` of the last messages stored, from the oldest to the newest
+    fn load_messages(&self, limit: usize) -> StorageResult<Vec<HistoryEntry>>;
+    /// Id of the last message stored (*`0` if there are none*)
+    fn last_message_id(&self) -> StorageResult<u64>;
+    /// Stores the last id assigned to a client, so ids aren't reused after a restart
+    fn save_last_client_id(&mut self, last_id: lnpkg::ClientId) -> StorageResult<()>;
+    /// Last id assigned to a client (*`0` if none has been assigned yet*)
+    fn load_last_client_id(&self) -> StorageResult<lnpkg::ClientId>;
+    /// Sets a setting of the account specified, replacing its previous value
+    fn set_setting(&mut self, account_id: AccountId, key: &str, value: &str) -> StorageResult<()>;
+    /// Settings of the account specified, sorted by their key
+    fn settings(&self, account_id: AccountId) -> StorageResult<Vec<(String, String)>>;
+    /// Hash of the secret that proves the ownership of the nickname (*`None` if nobody
+    /// has claimed it*)
+    fn nick_secret(&self, nick: &str) -> StorageResult<Option<String>>;
+    /// Claims the nickname, storing the hash of its secret
+    fn set_nick_secret(&mut self, nick: &str, secret_hash: &str) -> StorageResult<()>;
+    /// Stores a direct message until its recipient takes the nickname
+    fn queue_direct_message(&mut self, msg: &OfflineMessage) -> StorageResult<()>;
+    /// Removes and returns up to `limit` of the oldest messages addressed to the nickname,
+    /// in the order they were sent (*the rest stay stored*)
+    fn take_direct_messages(
+        &mut self,
+        nick: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<OfflineMessage>>;
+}
+
+/// State kept in memory, lost when the server stops
+#[derive(Default)]
+pub struct MemoryStorage {
+    messages: Vec<HistoryEntry>,
+    last_client_id: lnpkg::ClientId,
+    settings: BTreeMap<(AccountId, String), String>,
+    nick_secrets: HashMap<String, String>,
+    offline_messages: Vec<OfflineMessage>,
+}
+
+impl Storage for MemoryStorage {
+    fn save_message(&mut self, entry: &HistoryEntry) -> StorageResult<()> {
+        self.messages.push(entry.clone());
+        Ok(())
+    }
+
+    fn load_messages(&self, limit: usize) -> StorageResult<Vec<HistoryEntry>> {
+        let start = self.messages.len().saturating_sub(limit);
+        Ok(self.messages[start..].to_vec())
+    }
+
+    fn last_message_id(&self) -> StorageResult<u64> {
+        Ok(self.messages.last().map(|entry| entry.id).unwrap_or(0))
+    }
+
+    fn save_last_client_id(&mut self, last_id: lnpkg::ClientId) -> StorageResult<()> {
+        self.last_client_id = last_id;
+        Ok(())
+    }
+
+    fn load_last_client_id(&self) -> StorageResult<lnpkg::ClientId> {
+        Ok(self.last_client_id)
+    }
+
+    fn set_setting(&mut self, account_id: AccountId, key: &str, value: &str) -> StorageResult<()> {
+        self.settings
+            .insert((account_id, key.to_string()), value.to_string());
+        Ok(())
+    }
+
+    fn settings(&self, account_id: AccountId) -> StorageResult<Vec<(String, String)>> {
+        Ok(self
+            .settings
+            .iter()
+            .filter(|((id, _), _)| *id == account_id)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn nick_secret(&self, nick: &str) -> StorageResult<Option<String>> {
+        Ok(self.nick_secrets.get(nick).cloned())
+    }
+
+    fn set_nick_secret(&mut self, nick: &str, secret_hash: &str) -> StorageResult<()> {
+        self.nick_secrets
+            .insert(nick.to_string(), secret_hash.to_string());
+        Ok(())
+    }
+
+    fn queue_direct_message(&mut self, msg: &OfflineMessage) -> StorageResult<()> {
+        self.offline_messages.push(msg.clone());
+        Ok(())
+    }
+
+    fn take_direct_messages(
+        &mut self,
+        nick: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<OfflineMessage>> {
+        let mut taken = vec![];
+        self.offline_messages.retain(|msg| {
+            if msg.recipient == nick && taken.len() < limit {
+                taken.push(msg.clone());
+                false
+            } else {
+                true
+            }
+        });
+        Ok(taken)
+    }
+}
+
+/// Schema of the database, each migration takes it from the version of its index to
+/// the next one. The version of a database is kept in its `user_version`.
+/// <br>*Side note: Migrations must never be modified once released, changes to the
+/// schema are done by adding new ones.*
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        author_id INTEGER NOT NULL,
+        room TEXT NOT NULL,
+        msg TEXT NOT NULL
+    );
+    CREATE TABLE counters (
+        name TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE settings (
+        account_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (account_id, key)
+    );
+",
+    "
+    CREATE TABLE nicks (
+        nick TEXT PRIMARY KEY,
+        secret_hash TEXT NOT NULL
+    );
+    CREATE TABLE offline_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        recipient TEXT NOT NULL,
+        author_id INTEGER NOT NULL,
+        author_name TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        msg TEXT NOT NULL
+    );
+    CREATE INDEX offline_messages_recipient ON offline_messages (recipient);
+",
+    // The nicknames used to be stored as they were written, only the first one claimed
+    // is kept among the ones that only differ in case
+    "
+    DELETE FROM nicks WHERE rowid NOT IN (SELECT MIN(rowid) FROM nicks GROUP BY nick_key(nick));
+    UPDATE nicks SET nick = nick_key(nick);
+    UPDATE offline_messages SET recipient = nick_key(recipient);
+",
+];
+
+/// State stored in an SQLite database
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the database at the path specified (*creating it if it doesn't exist*),
+    /// and brings its schema up to date.
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+        Self::migrate(rusqlite::Connection::open(path)?)
+    }
+
+    /// Database that only lives in memory, lost when the server stops
+    pub fn open_in_memory() -> StorageResult<Self> {
+        Self::migrate(rusqlite::Connection::open_in_memory()?)
+    }
+
+    /// Applies the migrations that haven't been applied to the database yet
+    fn migrate(mut connection: rusqlite::Connection) -> StorageResult<Self> {
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(StorageError::UnknownVersion(version));
+        }
+        // Lowercases the nicknames like the server does (*`lower` only knows ASCII*)
+        connection.create_scalar_function(
+            "nick_key",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| Ok(nick_key(&ctx.get::<String>(0)?)),
+        )?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", i + 1)?;
+            transaction.commit()?;
+            println!("Applied migration {} to the database.", i + 1);
+        }
+        Ok(Self { connection })
+    }
+
+    /// Version of the schema of the database
+    pub fn version(&self) -> StorageResult<usize> {
+        Ok(self
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn save_message(&mut self, entry: &HistoryEntry) -> StorageResult<()> {
+        self.connection.execute(
+            "INSERT INTO messages (id, timestamp, author_id, room, msg) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                entry.id as i64,
+                entry.timestamp as i64,
+                entry.author_id as i64,
+                entry.room,
+                entry.msg
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_messages(&self, limit: usize) -> StorageResult<Vec<HistoryEntry>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, timestamp, author_id, room, msg FROM messages ORDER BY id DESC LIMIT ?1",
+        )?;
+        let mut messages = statement
+            .query_map([limit as i64], |row| {
+                Ok(HistoryEntry {
+                    id: row.get::<_, i64>(0)? as u64,
+                    timestamp: row.get::<_, i64>(1)? as u64,
+                    author_id: row.get::<_, i64>(2)? as lnpkg::ClientId,
+                    room: row.get(3)?,
+                    msg: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<HistoryEntry>, rusqlite::Error>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn last_message_id(&self) -> StorageResult<u64> {
+        let id: Option<i64> =
+            self.connection
+                .query_row("SELECT MAX(id) FROM messages", [], |row| row.get(0))?;
+        Ok(id.unwrap_or(0) as u64)
+    }
+
+    fn save_last_client_id(&mut self, last_id: lnpkg::ClientId) -> StorageResult<()> {
+        self.connection.execute(
+            "INSERT INTO counters (name, value) VALUES ('last_client_id', ?1)
+                ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+            [last_id as i64],
+        )?;
+        Ok(())
+    }
+
+    fn load_last_client_id(&self) -> StorageResult<lnpkg::ClientId> {
+        let id: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT value FROM counters WHERE name = 'last_client_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id.unwrap_or(0) as lnpkg::ClientId)
+    }
+
+    fn set_setting(&mut self, account_id: AccountId, key: &str, value: &str) -> StorageResult<()> {
+        self.connection.execute(
+            "INSERT INTO settings (account_id, key, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (account_id, key) DO UPDATE SET value = excluded.value",
+            params![account_id as i64, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn settings(&self, account_id: AccountId) -> StorageResult<Vec<(String, String)>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT key, value FROM settings WHERE account_id = ?1 ORDER BY key")?;
+        let settings = statement
+            .query_map([account_id as i64], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+        Ok(settings)
+    }
+
+    fn nick_secret(&self, nick: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT secret_hash FROM nicks WHERE nick = ?1",
+                [nick],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_nick_secret(&mut self, nick: &str, secret_hash: &str) -> StorageResult<()> {
+        self.connection.execute(
+            "INSERT INTO nicks (nick, secret_hash) VALUES (?1, ?2)
+                ON CONFLICT (nick) DO UPDATE SET secret_hash = excluded.secret_hash",
+            [nick, secret_hash],
+        )?;
+        Ok(())
+    }
+
+    fn queue_direct_message(&mut self, msg: &OfflineMessage) -> StorageResult<()> {
+        self.connection.execute(
+            "INSERT INTO offline_messages (recipient, author_id, author_name, timestamp, msg)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                msg.recipient,
+                msg.author_id as i64,
+                msg.author_name,
+                msg.timestamp as i64,
+                msg.msg
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn take_direct_messages(
+        &mut self,
+        nick: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<OfflineMessage>> {
+        let transaction = self.connection.transaction()?;
+        let rows = transaction
+            .prepare(
+                "SELECT id, author_id, author_name, timestamp, msg FROM offline_messages
+                    WHERE recipient = ?1 ORDER BY id LIMIT ?2",
+            )?
+            .query_map(
+                params![nick, i64::try_from(limit).unwrap_or(i64::MAX)],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        OfflineMessage {
+                            recipient: nick.to_string(),
+                            author_id: row.get::<_, i64>(1)? as lnpkg::ClientId,
+                            author_name: row.get(2)?,
+                            timestamp: row.get::<_, i64>(3)? as u64,
+                            msg: row.get(4)?,
+                        },
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<(i64, OfflineMessage)>, rusqlite::Error>>()?;
+        // Only the ones taken, the newer ones wait for the next delivery
+        if let Some((last_id, _)) = rows.last() {
+            transaction.execute(
+                "DELETE FROM offline_messages WHERE recipient = ?1 AND id <= ?2",
+                params![nick, last_id],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(rows.into_iter().map(|(_, msg)| msg).collect())
+    }
+}
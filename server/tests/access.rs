@@ -0,0 +1,92 @@
+use socks::access::{AccessList, IpRange};
+use std::{net::IpAddr, time::Duration};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn ip_range() {
+    let range: IpRange = "10.1.2.3/16".parse().unwrap();
+    assert_eq!("10.1.0.0/16", range.to_string());
+    assert!(range.contains(&ip("10.1.200.7")));
+    assert!(!range.contains(&ip("10.2.0.1")));
+    assert!(!range.contains(&ip("::1")));
+
+    let range: IpRange = "fd00::/8".parse().unwrap();
+    assert!(range.contains(&ip("fd12::1")));
+    assert!(!range.contains(&ip("fe80::1")));
+
+    assert_eq!(
+        "127.0.0.1",
+        "127.0.0.1".parse::<IpRange>().unwrap().to_string()
+    );
+    assert!("0.0.0.0/0"
+        .parse::<IpRange>()
+        .unwrap()
+        .contains(&ip("8.8.8.8")));
+    assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    assert!("localhost".parse::<IpRange>().is_err());
+}
+
+#[test]
+fn allow_list() {
+    let access = AccessList::new(vec!["192.168.0.0/24".parse().unwrap()]);
+    assert!(access.is_allowed(&ip("192.168.0.20")));
+    assert!(!access.is_allowed(&ip("192.168.1.20")));
+}
+
+#[test]
+fn persisted_bans() {
+    let path = std::env::temp_dir().join(format!("socks-bans-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut access = AccessList::open(&path, vec![]).unwrap();
+    access.ban("10.0.0.0/8".parse().unwrap(), None).unwrap();
+    access
+        .ban("1.2.3.4".parse().unwrap(), Some(Duration::from_secs(3600)))
+        .unwrap();
+    access
+        .ban("5.6.7.8".parse().unwrap(), Some(Duration::ZERO))
+        .unwrap();
+    assert!(!access.is_allowed(&ip("10.20.30.40")));
+    assert!(!access.is_allowed(&ip("1.2.3.4")));
+    // Already expired
+    assert!(access.is_allowed(&ip("5.6.7.8")));
+    // Lasts too long to tell when it expires
+    let error = access
+        .ban(
+            "9.9.9.9".parse().unwrap(),
+            Some(Duration::from_secs(u64::MAX)),
+        )
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, error.kind());
+    assert!(access.is_allowed(&ip("9.9.9.9")));
+    // Only the file of the list is left
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    assert!(!std::path::Path::new(&temp_path).exists());
+
+    let mut access = AccessList::open(&path, vec![]).unwrap();
+    assert_eq!(2, access.bans().len());
+    assert!(!access.is_allowed(&ip("10.20.30.40")));
+    assert!(access.unban(&"10.0.0.0/8".parse().unwrap()).unwrap());
+    assert!(!access.unban(&"10.0.0.0/8".parse().unwrap()).unwrap());
+
+    let access = AccessList::open(&path, vec![]).unwrap();
+    assert!(access.is_allowed(&ip("10.20.30.40")));
+    assert!(!access.is_allowed(&ip("1.2.3.4")));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unsaved_bans() {
+    // The directory of the file doesn't exist, so the bans can't be saved
+    let path = std::env::temp_dir()
+        .join(format!("socks-missing-{}", std::process::id()))
+        .join("bans.txt");
+    let mut access = AccessList::open(&path, vec![]).unwrap();
+    assert!(access.ban("1.2.3.4".parse().unwrap(), None).is_err());
+    assert!(access.is_allowed(&ip("1.2.3.4")));
+    assert!(access.bans().is_empty());
+}
@@ -0,0 +1,100 @@
+use socks::accounts::{AccountError, AccountStore, FileAccountStore, MemoryAccountStore};
+
+fn accounts_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "socks-accounts-{}-{}.txt",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Checks the behaviour every store must have
+fn register_and_login(store: &mut dyn AccountStore) {
+    let account = store.register("alice", "hunter2").unwrap();
+    assert_eq!("alice", account.username);
+    assert_ne!("hunter2", account.password_hash);
+    assert!(matches!(
+        store.register("alice", "other"),
+        Err(AccountError::UsernameTaken)
+    ));
+
+    assert_eq!(account.id, store.login("alice", "hunter2").unwrap().id);
+    assert!(matches!(
+        store.login("alice", "hunter3"),
+        Err(AccountError::InvalidCredentials)
+    ));
+    assert!(matches!(
+        store.login("bob", "hunter2"),
+        Err(AccountError::InvalidCredentials)
+    ));
+
+    let other = store.register("bob", "hunter2").unwrap();
+    assert_ne!(account.id, other.id);
+}
+
+#[test]
+fn memory_store() {
+    register_and_login(&mut MemoryAccountStore::default());
+}
+
+#[test]
+fn file_store() {
+    let path = accounts_path("file");
+    register_and_login(&mut FileAccountStore::open(&path).unwrap());
+
+    // Reopened
+    let mut store = FileAccountStore::open(&path).unwrap();
+    let alice = store.login("alice", "hunter2").unwrap();
+    let bob = store.login("bob", "hunter2").unwrap();
+    assert!(matches!(
+        store.register("alice", "other"),
+        Err(AccountError::UsernameTaken)
+    ));
+    // Ids aren't reused
+    let carol = store.register("carol", "pass").unwrap();
+    assert!(carol.id > alice.id.max(bob.id));
+
+    let store = FileAccountStore::open(&path).unwrap();
+    assert_eq!(carol.id, store.login("carol", "pass").unwrap().id);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn file_store_separators() {
+    let path = accounts_path("separators");
+    let mut store = FileAccountStore::open(&path).unwrap();
+    for username in ["al\tice", "al\nice", "alice\n"] {
+        assert!(matches!(
+            store.register(username, "hunter2"),
+            Err(AccountError::InvalidUsername)
+        ));
+    }
+    store.register("alice", "hunter2").unwrap();
+
+    // Nothing was written for the usernames rejected
+    let store = FileAccountStore::open(&path).unwrap();
+    assert!(store.find("alice").is_some());
+    assert!(store.find("al").is_none());
+    assert_eq!(1, std::fs::read_to_string(&path).unwrap().lines().count());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn file_store_unsaved() {
+    // The directory of the file doesn't exist, so the accounts can't be saved
+    let path = std::env::temp_dir()
+        .join(format!("socks-missing-{}", std::process::id()))
+        .join("accounts.txt");
+    let mut store = FileAccountStore::open(&path).unwrap();
+    assert!(matches!(
+        store.register("alice", "hunter2"),
+        Err(AccountError::Storage(_))
+    ));
+    assert!(store.find("alice").is_none());
+    assert!(matches!(
+        store.login("alice", "hunter2"),
+        Err(AccountError::InvalidCredentials)
+    ));
+}
@@ -0,0 +1,118 @@
+use socks::{
+    access::IpRange,
+    config::{Config, ConfigError},
+};
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Writes a config file with the content given, returning its path
+fn config_file(name: &str, content: &str) -> String {
+    let path =
+        std::env::temp_dir().join(format!("socks-config-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn example_file() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/socks.example.toml");
+    assert_eq!(Config::default(), Config::from_file(path).unwrap());
+}
+
+#[test]
+fn file() {
+    let path = config_file(
+        "file",
+        "bind = \"0.0.0.0:9000\"\nqueue_size = 8\nallow = [\"10.0.0.0/8\"]\n",
+    );
+    let config = Config::from_file(&path).unwrap();
+    assert_eq!("0.0.0.0:9000", config.bind);
+    assert_eq!(8, config.queue_size);
+    assert_eq!(vec!["10.0.0.0/8".parse::<IpRange>().unwrap()], config.allow);
+    // Missing options keep their default value
+    assert_eq!(
+        Config::default().heartbeat_interval,
+        config.heartbeat_interval
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn flags_override_file() {
+    let path = config_file("flags", "bind = \"0.0.0.0:9000\"\nqueue_size = 8\n");
+    let config = Config::from_args(args(&["--queue-size", "16", "--config", &path])).unwrap();
+    assert_eq!("0.0.0.0:9000", config.bind);
+    assert_eq!(16, config.queue_size);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(matches!(
+        Config::from_args(args(&["--queue-size", "many"])),
+        Err(ConfigError::InvalidValue(..))
+    ));
+    assert!(matches!(
+        Config::from_args(args(&["--queue-size"])),
+        Err(ConfigError::MissingValue(..))
+    ));
+    assert!(matches!(
+        Config::from_args(args(&["--colour", "blue"])),
+        Err(ConfigError::UnknownFlag(..))
+    ));
+}
+
+#[test]
+fn unknown_fields() {
+    let path = config_file("unknown", "bind = \"0.0.0.0:9000\"\ncolour = \"blue\"\n");
+    assert!(matches!(
+        Config::from_file(&path),
+        Err(ConfigError::Toml(..))
+    ));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn validation() {
+    assert!(Config::default().validate().is_ok());
+    for flags in [
+        &["--heartbeat-interval", "0"][..],
+        &["--heartbeat-interval", "60", "--heartbeat-timeout", "30"],
+        &["--queue-size", "0"],
+        &["--queue-size", "1"],
+        &["--nick-min-length", "10", "--nick-max-length", "5"],
+        // Guests are named `guest-<id>`
+        &["--nick-symbols", "_"],
+        &["--nick-max-length", "10"],
+        &["--reserved-nick", "guest-1"],
+    ] {
+        assert!(
+            matches!(Config::from_args(args(flags)), Err(ConfigError::Invalid(_))),
+            "{:?} should be rejected",
+            flags
+        );
+    }
+    assert!(Config::from_args(args(&[
+        "--heartbeat-timeout",
+        "30",
+        "--heartbeat-interval",
+        "30"
+    ]))
+    .is_ok());
+}
+
+#[test]
+fn file_validation() {
+    let path = config_file("invalid", "queue_size = 0\n");
+    assert!(matches!(
+        Config::from_file(&path),
+        Err(ConfigError::Invalid(_))
+    ));
+    assert!(matches!(
+        Config::from_args(args(&["--config", &path])),
+        Err(ConfigError::Invalid(_))
+    ));
+    // The flags are applied before validating
+    let config = Config::from_args(args(&["--config", &path, "--queue-size", "8"])).unwrap();
+    assert_eq!(8, config.queue_size);
+    let _ = std::fs::remove_file(&path);
+}
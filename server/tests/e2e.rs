@@ -0,0 +1,984 @@
+mod harness;
+
+use harness::{int, list, TestServer};
+use msg_templates::{
+    codec::{self, Codec},
+    framing,
+    shared::{self, capability},
+    Lnp, Lpty, Lpv,
+};
+use socks::config::Config;
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn connect_event() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let first_id = first.id;
+    let second = server.connect();
+
+    let event = first
+        .expect(|pkg| pkg.pkg_type == Lpty::EventClientConnected && int(pkg, "id") != first_id);
+    assert_eq!(second.id, int(&event, "id"));
+    assert_eq!(second.name, event.content["name"].to_string());
+}
+
+#[test]
+fn guest_names() {
+    let server = TestServer::start();
+    let first = server.connect();
+    let second = server.connect();
+    assert_eq!(format!("guest-{}", first.id), first.name);
+    assert_ne!(first.name, second.name);
+}
+
+#[test]
+fn preferred_nick() {
+    let server = TestServer::start();
+    let mut first = server.connect_as("alice");
+    assert_eq!("alice", first.name);
+
+    // Taken, or not valid: a guest name is given instead
+    let second = server.connect_as("Alice");
+    assert_eq!(format!("guest-{}", second.id), second.name);
+    let third = server.connect_as("not valid");
+    assert_eq!(format!("guest-{}", third.id), third.name);
+
+    // Guest names don't clash with the nicknames taken
+    let next_guest = format!("guest-{}", third.id + 1);
+    let result = first.command("chnick", &[&next_guest]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let fourth = server.connect();
+    assert_eq!(format!("{}-2", next_guest), fourth.name);
+}
+
+#[test]
+fn no_guest_names() {
+    // Not allowed by `Config::validate`, but the server must not hang anyway
+    let server = TestServer::start();
+    server.handle.server().lock().unwrap().nick_rules.symbols = "_".to_string();
+    let mut stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+    framing::write_frame(&mut stream, &harness::hello(None).as_bytes()).unwrap();
+
+    let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+    let error = msg_templates::Lnp::from_string(
+        &String::from_utf8(reader.read_frame().unwrap().unwrap()).unwrap(),
+    );
+    assert_eq!(
+        msg_templates::error_code::INVALID_NICKNAME,
+        int(&error, "code")
+    );
+    assert_eq!(None, reader.read_frame().unwrap());
+
+    // Clients with a valid nickname can still connect
+    let client = server.connect_as("alice");
+    assert_eq!("alice", client.name);
+}
+
+#[test]
+fn handshake_queue_too_small() {
+    // Not allowed by `Config::validate`, the handshake doesn't fit in the queue
+    let server = TestServer::start();
+    server.handle.server().lock().unwrap().outbound.queue_size = 1;
+    for _ in 0..5 {
+        let mut stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+        stream.set_read_timeout(Some(harness::TIMEOUT)).unwrap();
+        framing::write_frame(&mut stream, &harness::hello(None).as_bytes()).unwrap();
+
+        // Either the client gets its identity, or it's disconnected
+        let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+        while let Some(frame) = reader.read_frame().unwrap() {
+            let pkg = msg_templates::Lnp::from_string(&String::from_utf8(frame).unwrap());
+            if pkg.pkg_type == Lpty::Identity {
+                break;
+            }
+        }
+    }
+    assert!(!server.handle.server().is_poisoned());
+}
+
+#[test]
+fn handshake_required() {
+    let server = TestServer::start();
+    let mut stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+    let msg = msg_templates::client::msg("hi".to_string(), None);
+    framing::write_frame(&mut stream, msg.as_bytes().as_slice()).unwrap();
+
+    let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+    let error = msg_templates::Lnp::from_string(
+        &String::from_utf8(reader.read_frame().unwrap().unwrap()).unwrap(),
+    );
+    assert_eq!(
+        msg_templates::error_code::HANDSHAKE_REQUIRED,
+        int(&error, "code")
+    );
+    assert_eq!(None, reader.read_frame().unwrap());
+}
+
+#[test]
+fn welcome() {
+    let server = TestServer::start();
+    let mut hello = harness::hello(None);
+    hello.content.insert(
+        "capabilities".to_string(),
+        Lpv::List(vec![
+            capability::HISTORY.to_string(),
+            "teleportation".to_string(),
+        ]),
+    );
+    let mut client = server.connect_with(hello);
+
+    let welcome = client.expect(|pkg| msg_templates::command_name(pkg) == Some("welcome"));
+    assert_eq!(shared::PROTOCOL_VERSION, int(&welcome, "version"));
+    // Unknown capabilities are left out
+    assert_eq!(vec![capability::HISTORY], list(&welcome, "capabilities"));
+}
+
+#[test]
+fn incompatible_version() {
+    let server = TestServer::start();
+    let newer = Some(Lpv::Int(shared::PROTOCOL_VERSION + 1));
+    // Missing, or not even a number
+    let not_valid = Some(Lpv::String("one".to_string()));
+    for version in [newer, None, not_valid] {
+        let mut hello = harness::hello(None);
+        match version {
+            Some(version) => hello.content.insert("version".to_string(), version),
+            None => hello.content.remove("version"),
+        };
+        let mut stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+        framing::write_frame(&mut stream, hello.as_bytes().as_slice()).unwrap();
+
+        let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+        let error = msg_templates::Lnp::from_string(
+            &String::from_utf8(reader.read_frame().unwrap().unwrap()).unwrap(),
+        );
+        assert_eq!(
+            msg_templates::error_code::INCOMPATIBLE_VERSION,
+            int(&error, "code")
+        );
+        assert_eq!(None, reader.read_frame().unwrap());
+    }
+}
+
+#[test]
+fn history_capability() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    first.send(msg_templates::client::msg("hello there".to_string(), None));
+    first.expect(|pkg| pkg.pkg_type == Lpty::Message);
+
+    let hello = shared::hello("plain".to_string(), "0.0.0".to_string(), vec![], None);
+    let mut second = server.connect_with(hello);
+    // Everything queued on connect arrives before the result
+    second.command("whoami", &[]);
+    assert!(!second.received(|pkg| pkg.pkg_type == Lpty::Message));
+}
+
+#[test]
+fn json_codec() {
+    let server = TestServer::start();
+    let mut json = server.connect_with_codec(&codec::JSON);
+    let mut lnpkg = server.connect();
+
+    let welcome = json.expect(|pkg| msg_templates::command_name(pkg) == Some("welcome"));
+    assert_eq!("json", welcome.content["codec"].to_string());
+
+    // Text that can't be carried by lnpkg reaches the clients speaking JSON untouched
+    let text = "key=value: and more";
+    json.send(msg_templates::client::msg(text.to_string(), None));
+    let msg = json.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    assert_eq!(text, msg.content["msg"].to_string());
+    assert_eq!(json.id, int(&msg, "client"));
+
+    lnpkg.send(msg_templates::client::msg("hi".to_string(), None));
+    let msg = json.expect(|pkg| pkg.pkg_type == Lpty::Message && int(pkg, "client") == lnpkg.id);
+    assert_eq!("hi", msg.content["msg"].to_string());
+
+    json.send_raw(b"not json\n");
+    let error = json.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+    assert_eq!(
+        msg_templates::error_code::NON_VALID_FORMAT,
+        int(&error, "code")
+    );
+}
+
+#[test]
+fn json_codec_leading_whitespace() {
+    let server = TestServer::start();
+    let mut stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+    stream.write_all(b"\r\n ").unwrap();
+    stream
+        .write_all(&codec::JSON.encode_frame(&harness::hello(None)).unwrap())
+        .unwrap();
+
+    let mut reader = framing::FrameReader::with_framing(
+        stream,
+        codec::JSON.framing(),
+        framing::DEFAULT_MAX_FRAME_SIZE,
+    );
+    let welcome = harness::decode(
+        &codec::JSON,
+        &String::from_utf8(reader.read_frame().unwrap().unwrap()).unwrap(),
+    );
+    assert_eq!("json", welcome.content["codec"].to_string());
+}
+
+#[test]
+fn broadcast_message() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let mut second = server.connect();
+    let first_id = first.id;
+
+    first.send(msg_templates::client::msg("hello there".to_string(), None));
+    for client in [&mut first, &mut second] {
+        let msg = client.expect(|pkg| pkg.pkg_type == Lpty::Message);
+        assert_eq!("hello there", msg.content["msg"].to_string());
+        assert_eq!(first_id, int(&msg, "client"));
+    }
+}
+
+#[test]
+fn history() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    for i in 1..=3 {
+        first.send(msg_templates::client::msg(format!("message {}", i), None));
+        first.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    }
+
+    // Replayed right after the identity
+    let mut second = server.connect();
+    let mut ids = vec![];
+    for i in 1..=3 {
+        let msg = second.expect(|pkg| pkg.pkg_type == Lpty::Message);
+        assert_eq!(format!("message {}", i), msg.content["msg"].to_string());
+        ids.push(int(&msg, "msg_id"));
+    }
+
+    let result = second.command("history", &["1", &ids[2].to_string()]);
+    assert_eq!(1, int(&result, "payload"));
+    let msg = second.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    assert_eq!(ids[1], int(&msg, "msg_id"));
+}
+
+#[test]
+fn history_replay_fits_in_queue() {
+    let server = TestServer::start_with(socks::ServerBuilder::from_config(Config {
+        queue_size: 4,
+        history_replay: 20,
+        ..Config::default()
+    }));
+    let mut first = server.connect();
+    for i in 1..=10 {
+        first.send(msg_templates::client::msg(format!("message {}", i), None));
+        first.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    }
+
+    // Only the newest messages that fit after the welcome and the identity
+    let mut second = server.connect();
+    let result = second.command("whoami", &[]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let mut replayed = vec![];
+    while second.received(|pkg| pkg.pkg_type == Lpty::Message) {
+        let msg = second.expect(|pkg| pkg.pkg_type == Lpty::Message);
+        replayed.push(msg.content["msg"].to_string());
+    }
+    assert!((2..=4).contains(&replayed.len()), "{:?}", replayed);
+    let first_replayed = 11 - replayed.len();
+    let expected: Vec<String> = (first_replayed..=10)
+        .map(|i| format!("message {}", i))
+        .collect();
+    assert_eq!(expected, replayed);
+}
+
+#[test]
+fn persistence() {
+    let path = std::env::temp_dir().join(format!("socks-e2e-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let start = || {
+        let storage = socks::storage::SqliteStorage::open(&path).unwrap();
+        TestServer::start_with(socks::ServerBuilder::new().storage(Box::new(storage)))
+    };
+
+    let server = start();
+    let mut client = server.connect();
+    let first_id = client.id;
+    client.send(msg_templates::client::msg(
+        "before restart".to_string(),
+        None,
+    ));
+    let msg = client.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    let msg_id = int(&msg, "msg_id");
+    drop(client);
+    drop(server);
+
+    let server = start();
+    let mut client = server.connect();
+    // Ids aren't reused, and the history is still there
+    assert!(client.id > first_id);
+    let msg = client.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    assert_eq!("before restart", msg.content["msg"].to_string());
+    client.send(msg_templates::client::msg(
+        "after restart".to_string(),
+        None,
+    ));
+    let msg = client.expect(|pkg| {
+        pkg.pkg_type == Lpty::Message && pkg.content["msg"].to_string() == "after restart"
+    });
+    assert!(int(&msg, "msg_id") > msg_id);
+    drop(server);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn settings() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    let result = client.command("set", &["theme", "dark"]);
+    assert_eq!(
+        msg_templates::error_code::NOT_LOGGED_IN,
+        int(&result, "code")
+    );
+
+    client.command("register", &["someone", "password"]);
+    let result = client.command("set", &["theme", "dark"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let result = client.command("settings", &[]);
+    assert_eq!(vec!["theme=dark".to_string()], list(&result, "payload"));
+}
+
+#[test]
+fn direct_message() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let mut second = server.connect();
+
+    first.send(msg_templates::client::direct_message(
+        second.id,
+        "only for you".to_string(),
+    ));
+    let dm = second.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+    assert_eq!("only for you", dm.content["msg"].to_string());
+    assert_eq!(first.id, int(&dm, "client"));
+    assert_eq!(first.name, dm.content["name"].to_string());
+
+    let receipt = first.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_status"));
+    assert_eq!(
+        second.id.to_string(),
+        receipt.content["recipient"].to_string()
+    );
+    assert_eq!("delivered", receipt.content["status"].to_string());
+}
+
+#[test]
+fn group_direct_message() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let mut second = server.connect();
+    let mut third = server.connect();
+    let missing_id = third.id + 100;
+
+    first.send(msg_templates::client::group_direct_message(
+        vec![second.id, third.id, missing_id],
+        "for the group".to_string(),
+    ));
+    for client in [&mut second, &mut third] {
+        let msg = client.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+        assert_eq!("for the group", msg.content["msg"].to_string());
+        assert_eq!(first.id, int(&msg, "client"));
+    }
+    let report = first.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_report"));
+    assert_eq!(
+        vec![second.id.to_string(), third.id.to_string()],
+        list(&report, "delivered")
+    );
+    assert_eq!(vec![missing_id.to_string()], list(&report, "failed"));
+}
+
+#[test]
+fn offline_direct_message() {
+    let server = TestServer::start();
+    let mut owner = server.connect();
+    let result = owner.command("chnick", &["alice", "s3cret"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let mut sender = server.connect();
+    drop(owner);
+    sender.expect(|pkg| pkg.pkg_type == Lpty::EventClientLeft);
+
+    for msg in ["first", "second"] {
+        sender.send(msg_templates::client::nick_direct_message(
+            "alice".to_string(),
+            msg.to_string(),
+        ));
+        let status =
+            sender.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_status"));
+        assert_eq!("queued", status.content["status"].to_string());
+    }
+    // Nobody owns the nickname
+    sender.send(msg_templates::client::nick_direct_message(
+        "nobody".to_string(),
+        "hello?".to_string(),
+    ));
+    let error = sender.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+    assert_eq!(msg_templates::error_code::UNKNOWN_USER, int(&error, "code"));
+
+    let mut owner = server.connect();
+    let result = owner.command("chnick", &["alice", "wrong"]);
+    assert_eq!(
+        msg_templates::error_code::INVALID_CREDENTIALS,
+        int(&result, "code")
+    );
+    owner.command("chnick", &["alice", "s3cret"]);
+    for msg in ["first", "second"] {
+        let dm = owner.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+        assert_eq!(msg, dm.content["msg"].to_string());
+        assert_eq!(sender.id, int(&dm, "client"));
+        assert!(int(&dm, "timestamp") > 0);
+    }
+
+    // Delivered right away while the owner is connected
+    sender.send(msg_templates::client::nick_direct_message(
+        "alice".to_string(),
+        "third".to_string(),
+    ));
+    let status = sender.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_status"));
+    assert_eq!("delivered", status.content["status"].to_string());
+    let dm = owner.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+    assert_eq!("third", dm.content["msg"].to_string());
+}
+
+#[test]
+fn digits_nick_direct_message() {
+    let server = TestServer::start();
+    let mut owner = server.connect();
+    let result = owner.command("chnick", &["123", "s3cret"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let mut sender = server.connect();
+
+    // Sent to the nickname, not to the client whose id is 123
+    sender.send(msg_templates::client::nick_direct_message(
+        "123".to_string(),
+        "psst".to_string(),
+    ));
+    let status = sender.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_status"));
+    assert_eq!("delivered", status.content["status"].to_string());
+    let dm = owner.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+    assert_eq!("psst", dm.content["msg"].to_string());
+    assert_eq!(sender.id, int(&dm, "client"));
+}
+
+#[test]
+fn offline_direct_messages_fit_in_queue() {
+    let server = TestServer::start_with(socks::ServerBuilder::from_config(Config {
+        queue_size: 4,
+        ..Config::default()
+    }));
+    let mut owner = server.connect();
+    owner.command("chnick", &["alice", "s3cret"]);
+    let mut sender = server.connect();
+    drop(owner);
+    sender.expect(|pkg| pkg.pkg_type == Lpty::EventClientLeft);
+    let sent: Vec<String> = (1..=20).map(|i| format!("message {}", i)).collect();
+    for msg in sent.iter() {
+        sender.send(msg_templates::client::nick_direct_message(
+            "alice".to_string(),
+            msg.clone(),
+        ));
+        let status =
+            sender.expect(|pkg| msg_templates::command_name(pkg) == Some("delivery_status"));
+        assert_eq!("queued", status.content["status"].to_string());
+    }
+
+    // Each claim delivers the ones that fit, the rest wait for the next one
+    let mut owner = server.connect();
+    let mut delivered = vec![];
+    for _ in 0..sent.len() {
+        let result = owner.command("chnick", &["alice", "s3cret"]);
+        assert_eq!("ok", result.content["status"].to_string());
+        while owner.received(|pkg| pkg.pkg_type == Lpty::DirectMessage) {
+            let dm = owner.expect(|pkg| pkg.pkg_type == Lpty::DirectMessage);
+            delivered.push(dm.content["msg"].to_string());
+        }
+        if delivered.len() == sent.len() {
+            break;
+        }
+    }
+    assert_eq!(sent, delivered);
+}
+
+#[test]
+fn chnick_and_whoami() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    let result = client.command("chnick", &["someone"]);
+    assert_eq!("ok", result.content["status"].to_string());
+
+    let result = client.command("whoami", &[]);
+    assert_eq!("someone", result.content["payload"].to_string());
+    let identity = client.expect(|pkg| pkg.pkg_type == Lpty::SelfIdentity);
+    assert_eq!(client.id, int(&identity, "id"));
+    assert_eq!("someone", identity.content["name"].to_string());
+}
+
+#[test]
+fn nick_rules_and_rename_event() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let mut second = server.connect();
+
+    let result = first.command("chnick", &["Alice"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let event = second.expect(|pkg| msg_templates::command_name(pkg) == Some("client_renamed"));
+    assert_eq!(first.id, int(&event, "id"));
+    assert_eq!("Alice", event.content["new"].to_string());
+
+    let cases = [
+        ("alice", msg_templates::error_code::NICKNAME_TAKEN),
+        ("server", msg_templates::error_code::RESERVED_NICKNAME),
+        ("not valid", msg_templates::error_code::INVALID_NICKNAME),
+    ];
+    for (nick, code) in cases {
+        let result = second.command("chnick", &[nick]);
+        assert_eq!("err", result.content["status"].to_string());
+        assert_eq!(code, int(&result, "code"));
+    }
+
+    // Changing the case of its own nickname is allowed
+    let result = first.command("chnick", &["ALICE"]);
+    assert_eq!("ok", result.content["status"].to_string());
+}
+
+#[test]
+fn command_error() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    let result = client.command("chnick", &[]);
+    assert_eq!("err", result.content["status"].to_string());
+    assert_eq!(
+        msg_templates::error_code::NON_VALID_COMMAND_USAGE,
+        int(&result, "code")
+    );
+    // The client is still connected
+    let result = client.command("whoami", &[]);
+    assert_eq!("ok", result.content["status"].to_string());
+}
+
+#[test]
+fn identity_request() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let second = server.connect_as("second");
+
+    first.send(msg_templates::client::id_request(second.id));
+    let identity =
+        first.expect(|pkg| pkg.pkg_type == Lpty::Identity && int(pkg, "id") == second.id);
+    assert_eq!("second", identity.content["name"].to_string());
+
+    first.send(msg_templates::client::id_request(second.id + 100));
+    let error = first.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+    assert_eq!(msg_templates::error_code::UNKNOWN_USER, int(&error, "code"));
+}
+
+#[test]
+fn heartbeat() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let client_id = client.id;
+    let shared = server.handle.server();
+    let heartbeat = |server: &socks::comm_elements::Server| {
+        (server.heartbeat.interval, server.heartbeat.timeout)
+    };
+    let (interval, timeout) = heartbeat(&shared.lock().unwrap());
+    // Makes the client look idle for the time given, and runs the heartbeat checks
+    let set_idle = |idle: Duration| {
+        let mut server_guard = shared.lock().unwrap();
+        let client = server_guard.clients.get_mut(&client_id).unwrap();
+        client.last_activity = Instant::now() - idle;
+        server_guard.check_heartbeats()
+    };
+    let idle = || {
+        shared.lock().unwrap().clients[&client_id]
+            .last_activity
+            .elapsed()
+    };
+
+    // Idle for longer than the interval: pinged, and the pong counts as activity
+    assert!(set_idle(interval + Duration::from_secs(1)).is_empty());
+    client.expect(|pkg| msg_templates::command_name(pkg) == Some("ping"));
+    client.send(msg_templates::client::pong());
+    let deadline = Instant::now() + harness::TIMEOUT;
+    while idle() >= interval {
+        assert!(Instant::now() < deadline, "The pong wasn't received.");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // Idle for longer than the timeout: disconnected
+    assert_eq!(vec![client_id], set_idle(timeout + Duration::from_secs(1)));
+    client.expect_disconnected();
+}
+
+#[test]
+fn malformed_package_strikes() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    // A message without its `msg` key
+    let malformed = || Lnp::from_hashmap(HashMap::new(), Lpty::Message);
+    let send_malformed = |client: &mut harness::ScriptedClient| {
+        client.send(malformed());
+        let error = client.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+        assert_eq!(
+            msg_templates::error_code::NON_VALID_FORMAT,
+            int(&error, "code")
+        );
+    };
+
+    // A valid package in between resets the strikes
+    send_malformed(&mut client);
+    send_malformed(&mut client);
+    let result = client.command("whoami", &[]);
+    assert_eq!("ok", result.content["status"].to_string());
+    send_malformed(&mut client);
+    send_malformed(&mut client);
+    let result = client.command("whoami", &[]);
+    assert_eq!("ok", result.content["status"].to_string());
+
+    // The third one in a row is fatal
+    send_malformed(&mut client);
+    send_malformed(&mut client);
+    client.send(malformed());
+    client.expect_disconnected();
+}
+
+/// Server whose outbound queues hold a few packages, with the slow consumer timeout given
+fn start_with_small_queues(slow_consumer_timeout: u64) -> TestServer {
+    let config = Config {
+        queue_size: 4,
+        slow_consumer_timeout,
+        ..Config::default()
+    };
+    TestServer::start_with(socks::ServerBuilder::from_config(config))
+}
+
+/// Sends big packages to the client (*which isn't reading them*) until its outbound
+/// queue is full, returns the number of packages that were queued.
+fn fill_outbox(server: &TestServer, client_id: lnpkg::ClientId) -> usize {
+    let shared = server.handle.server();
+    let big = msg_templates::client::msg("x".repeat(512 * 1024), None);
+    let mut queued = 0;
+    let mut was_full = false;
+    for _ in 0..1000 {
+        match shared.lock().unwrap().send_msg(&client_id, &big) {
+            Ok(()) => {
+                queued += 1;
+                was_full = false;
+            }
+            // Still full after giving the writer time to fill the buffers of the socket
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && was_full => return queued,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => was_full = true,
+            Err(e) => panic!("Couldn't queue the package: {:?}", e),
+        }
+        if was_full {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    panic!("The outbound queue of client {} never got full.", client_id);
+}
+
+fn is_big_message(pkg: &Lnp) -> bool {
+    pkg.pkg_type == Lpty::Message && pkg.content["msg"].to_string().len() > 1024
+}
+
+#[test]
+fn slow_consumer_disconnected() {
+    let server = start_with_small_queues(1);
+    let mut slow = server.connect();
+    let slow_id = slow.id;
+    let shared = server.handle.server();
+    let full_since = || shared.lock().unwrap().clients[&slow_id].outbox_full_since;
+
+    // Full while the client doesn't read, but not anymore once it catches up, even
+    // if nothing else is sent to it
+    let queued = fill_outbox(&server, slow_id);
+    assert!(full_since().is_some());
+    for _ in 0..queued {
+        slow.expect(is_big_message);
+    }
+    let deadline = Instant::now() + harness::TIMEOUT;
+    while !shared.lock().unwrap().clients[&slow_id].outbox.is_empty() {
+        assert!(Instant::now() < deadline, "The queue wasn't emptied.");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(shared.lock().unwrap().check_slow_consumers().is_empty());
+    assert_eq!(None, full_since());
+
+    // Full for longer than allowed
+    fill_outbox(&server, slow_id);
+    shared
+        .lock()
+        .unwrap()
+        .clients
+        .get_mut(&slow_id)
+        .unwrap()
+        .outbox_full_since = Some(Instant::now() - Duration::from_secs(2));
+    assert_eq!(vec![slow_id], shared.lock().unwrap().check_slow_consumers());
+    slow.expect_disconnected();
+}
+
+#[test]
+fn slow_consumer_dropped_messages() {
+    let server = start_with_small_queues(0);
+    let mut slow = server.connect();
+    let mut other = server.connect();
+    let slow_id = slow.id;
+    let shared = server.handle.server();
+
+    // Never disconnected, no matter how long the queue has been full
+    let queued = fill_outbox(&server, slow_id);
+    shared
+        .lock()
+        .unwrap()
+        .clients
+        .get_mut(&slow_id)
+        .unwrap()
+        .outbox_full_since = Some(Instant::now() - Duration::from_secs(3600));
+    assert!(shared.lock().unwrap().check_slow_consumers().is_empty());
+
+    // The rest of clients still get the messages
+    other.send(msg_templates::client::msg("hello".to_string(), None));
+    let msg = other.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    assert_eq!("hello", msg.content["msg"].to_string());
+
+    // The slow client only misses the packages that didn't fit
+    for _ in 0..queued {
+        slow.expect(is_big_message);
+    }
+    let result = slow.command("whoami", &[]);
+    assert_eq!("ok", result.content["status"].to_string());
+}
+
+#[test]
+fn broadcast_report() {
+    let server = start_with_small_queues(0);
+    let mut first = server.connect();
+    let second = server.connect();
+    let third = server.connect();
+    let (first_id, second_id, third_id) = (first.id, second.id, third.id);
+    let shared = server.handle.server();
+
+    fill_outbox(&server, second_id);
+    // The writer of the third client stops once it fails to write to the stream
+    shared.lock().unwrap().clients[&third_id]
+        .stream
+        .shutdown(std::net::Shutdown::Write)
+        .unwrap();
+
+    let msg = msg_templates::client::msg("report".to_string(), None);
+    let deadline = Instant::now() + harness::TIMEOUT;
+    let report = loop {
+        let report = shared.lock().unwrap().broadcast_msg(&msg);
+        if !report.evicted.is_empty() {
+            break report;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "The third client wasn't evicted."
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(vec![first_id], report.delivered);
+    assert_eq!(vec![second_id], report.dropped);
+    assert_eq!(vec![third_id], report.evicted);
+
+    // Evicted clients are disconnected, and the rest notified
+    assert!(!shared.lock().unwrap().clients.contains_key(&third_id));
+    let event = first.expect(|pkg| pkg.pkg_type == Lpty::EventClientLeft);
+    assert_eq!(third_id, int(&event, "id"));
+}
+
+#[test]
+fn disconnect_event() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let second = server.connect();
+    let second_id = second.id;
+
+    drop(second);
+    let event = first.expect(|pkg| pkg.pkg_type == Lpty::EventClientLeft);
+    assert_eq!(second_id, int(&event, "id"));
+}
+
+#[test]
+fn rooms() {
+    let server = TestServer::start();
+    let mut first = server.connect();
+    let mut second = server.connect();
+    let mut third = server.connect();
+    let first_id = first.id;
+
+    let result = first.command("join", &["rust"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    second.command("join", &["rust"]);
+    let second_id = second.id;
+    let event = first.expect(|pkg| {
+        msg_templates::command_name(pkg) == Some("room_joined") && int(pkg, "id") == second_id
+    });
+    assert_eq!("rust", event.content["room"].to_string());
+
+    first.send(msg_templates::client::msg(
+        "only for the room".to_string(),
+        Some("rust".to_string()),
+    ));
+    let msg = second.expect(|pkg| pkg.pkg_type == Lpty::Message);
+    assert_eq!("rust", msg.content["room"].to_string());
+    assert_eq!(first_id, int(&msg, "client"));
+
+    // Not a member of the room
+    third.send(msg_templates::client::msg(
+        "intruder".to_string(),
+        Some("rust".to_string()),
+    ));
+    let error = third.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+    assert_eq!(
+        msg_templates::error_code::RESOURCE_NOT_AVAILABLE,
+        int(&error, "code")
+    );
+    third.send(msg_templates::client::msg("lobby".to_string(), None));
+    let msg = first.expect(|pkg| {
+        pkg.pkg_type == Lpty::Message && pkg.content["room"].to_string() == msg_templates::LOBBY
+    });
+    assert_eq!("lobby", msg.content["msg"].to_string());
+
+    second.command("part", &["rust"]);
+    let event = first.expect(|pkg| msg_templates::command_name(pkg) == Some("room_parted"));
+    assert_eq!(second.id, int(&event, "id"));
+}
+
+#[test]
+fn moderation() {
+    let server = TestServer::start_with(socks::ServerBuilder::from_config(Config {
+        oper_password: Some("secret".to_string()),
+        ..Config::default()
+    }));
+    let mut operator = server.connect();
+    let mut target = server.connect();
+    let target_id = target.id.to_string();
+
+    // Not an operator yet
+    let result = operator.command("kick", &[&target_id]);
+    assert_eq!(
+        msg_templates::error_code::PERMISSION_DENIED,
+        int(&result, "code")
+    );
+    let result = operator.command("oper", &["wrong"]);
+    assert_eq!(
+        msg_templates::error_code::INVALID_CREDENTIALS,
+        int(&result, "code")
+    );
+    let result = operator.command("oper", &["secret"]);
+    assert_eq!("ok", result.content["status"].to_string());
+
+    // Too long to tell when it ends
+    let result = operator.command("mute", &[&target_id, &u64::MAX.to_string()]);
+    assert_eq!(
+        msg_templates::error_code::NON_VALID_COMMAND_USAGE,
+        int(&result, "code")
+    );
+    assert!(!target.received(|pkg| msg_templates::command_name(pkg) == Some("moderation")));
+
+    let result = operator.command("mute", &[&target_id, "60"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let event = target.expect(|pkg| msg_templates::command_name(pkg) == Some("moderation"));
+    assert_eq!("mute", event.content["action"].to_string());
+    assert_eq!(60, int(&event, "duration"));
+    target.send(msg_templates::client::msg("hello?".to_string(), None));
+    let error = target.expect(|pkg| msg_templates::command_name(pkg) == Some("error"));
+    assert_eq!(msg_templates::error_code::MUTED, int(&error, "code"));
+
+    let result = operator.command("kick", &[&target_id]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let event = operator.expect(|pkg| {
+        msg_templates::command_name(pkg) == Some("moderation")
+            && pkg.content["action"].to_string() == "kick"
+    });
+    assert_eq!(target.id, int(&event, "id"));
+    target.expect_disconnected();
+}
+
+#[test]
+fn oper_attempts() {
+    let server = TestServer::start_with(socks::ServerBuilder::from_config(Config {
+        oper_password: Some("secret".to_string()),
+        ..Config::default()
+    }));
+    let mut client = server.connect();
+    for _ in 0..4 {
+        let result = client.command("oper", &["wrong"]);
+        assert_eq!(
+            msg_templates::error_code::INVALID_CREDENTIALS,
+            int(&result, "code")
+        );
+    }
+    // Even when the error is reported in the result of a command, too many wrong
+    // guesses are fatal
+    client.send(msg_templates::client::command(
+        "oper".to_string(),
+        vec!["wrong".to_string()],
+        None,
+    ));
+    client.expect_disconnected();
+}
+
+#[test]
+fn ban_and_unban() {
+    let server = TestServer::start_with(socks::ServerBuilder::from_config(Config {
+        oper_password: Some("secret".to_string()),
+        ..Config::default()
+    }));
+    let mut operator = server.connect();
+    let mut target = server.connect();
+    operator.command("oper", &["secret"]);
+
+    let result = operator.command("ban", &[&target.id.to_string()]);
+    assert_eq!("127.0.0.1", result.content["payload"].to_string());
+    target.expect_disconnected();
+
+    // Every test client connects from the loopback address
+    expect_refused(&server);
+    let result = operator.command("unban", &["127.0.0.1"]);
+    assert_eq!("ok", result.content["status"].to_string());
+    let mut target = server.connect();
+
+    // Too long to tell when it expires
+    let result = operator.command("ban", &[&target.id.to_string(), &u64::MAX.to_string()]);
+    assert_eq!(
+        msg_templates::error_code::NON_VALID_COMMAND_USAGE,
+        int(&result, "code")
+    );
+
+    // Bans of ranges kick every client inside them (*except the operator*)
+    let result = operator.command("ban", &["127.0.0.0/8", "3600"]);
+    assert_eq!("127.0.0.0/8", result.content["payload"].to_string());
+    target.expect_disconnected();
+    expect_refused(&server);
+    let bans = server.handle.server().lock().unwrap().access.bans().len();
+    assert_eq!(1, bans);
+}
+
+fn expect_refused(server: &TestServer) {
+    let stream = std::net::TcpStream::connect(server.handle.local_addr()).unwrap();
+    let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+    let error = msg_templates::Lnp::from_string(
+        &String::from_utf8(reader.read_frame().unwrap().unwrap()).unwrap(),
+    );
+    assert_eq!(msg_templates::error_code::BANNED, int(&error, "code"));
+    assert_eq!(None, reader.read_frame().unwrap());
+}
@@ -0,0 +1,224 @@
+//! Harness for end-to-end tests: boots a server on an ephemeral loopback port and
+//! drives scripted clients that talk to it through real TCP connections.
+#![allow(dead_code)]
+
+use msg_templates::{
+    codec::{self, Codec},
+    framing,
+    shared::capability,
+    Lnp, Lpty, Lpv,
+};
+use std::{
+    io::Write,
+    net,
+    time::{Duration, Instant},
+};
+
+/// Maximum time a client waits for an expected package
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Server running in the background, shut down when dropped
+pub struct TestServer {
+    pub handle: socks::ServerHandle,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        Self::start_with(socks::ServerBuilder::new())
+    }
+
+    /// Starts the server built by the builder given, bound to an ephemeral port
+    pub fn start_with(builder: socks::ServerBuilder) -> Self {
+        let handle = builder.bind("127.0.0.1:0").build().unwrap().spawn();
+        Self { handle }
+    }
+
+    /// Connects a new client, waiting until the server has sent its identity
+    pub fn connect(&self) -> ScriptedClient {
+        ScriptedClient::connect(self.handle.local_addr(), hello(None))
+    }
+
+    /// Same as `connect`, but the client asks for the nickname given in its hello
+    pub fn connect_as(&self, nick: &str) -> ScriptedClient {
+        ScriptedClient::connect(self.handle.local_addr(), hello(Some(nick.to_string())))
+    }
+
+    /// Same as `connect`, but the client starts with the hello package given
+    pub fn connect_with(&self, hello: Lnp) -> ScriptedClient {
+        ScriptedClient::connect(self.handle.local_addr(), hello)
+    }
+
+    /// Same as `connect`, but the client speaks the codec given
+    pub fn connect_with_codec(&self, codec: &'static dyn Codec) -> ScriptedClient {
+        ScriptedClient::connect_with_codec(self.handle.local_addr(), hello(None), codec)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+    }
+}
+
+/// Client whose actions are driven by the test
+pub struct ScriptedClient {
+    pub id: lnpkg::ClientId,
+    pub name: String,
+    writer: net::TcpStream,
+    reader: framing::FrameReader<net::TcpStream>,
+    codec: &'static dyn Codec,
+    /// Frames received but not consumed yet by `expect`
+    pending: Vec<String>,
+    request_id: i128,
+}
+
+impl ScriptedClient {
+    pub fn connect(addr: net::SocketAddr, hello: Lnp) -> Self {
+        Self::connect_with_codec(addr, hello, &codec::LNPKG)
+    }
+
+    pub fn connect_with_codec(
+        addr: net::SocketAddr,
+        hello: Lnp,
+        codec: &'static dyn Codec,
+    ) -> Self {
+        let stream = net::TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut client = Self {
+            id: 0,
+            name: String::new(),
+            writer: stream.try_clone().unwrap(),
+            reader: framing::FrameReader::with_framing(
+                stream,
+                codec.framing(),
+                framing::DEFAULT_MAX_FRAME_SIZE,
+            ),
+            codec,
+            pending: vec![],
+            request_id: 0,
+        };
+
+        client.send(hello);
+        let identity = client.expect(|pkg| pkg.pkg_type == Lpty::Identity);
+        client.id = int(&identity, "id");
+        client.name = identity.content["name"].to_string();
+        client
+    }
+
+    pub fn send(&mut self, pkg: Lnp) {
+        self.send_raw(&self.codec.encode_frame(&pkg).unwrap());
+    }
+
+    /// Writes the bytes given as they are (*the framing included*)
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).unwrap();
+    }
+
+    /// Reads the next frame sent by the server, panicking if nothing arrives in time
+    pub fn recv_raw(&mut self) -> String {
+        match self.reader.read_frame() {
+            Ok(Some(frame)) => String::from_utf8(frame).unwrap(),
+            Ok(None) => panic!("Client {} was disconnected by the server.", self.id),
+            Err(e) => panic!("Client {} didn't receive anything: {:?}", self.id, e),
+        }
+    }
+
+    /// Returns the first frame (*received or pending*) that matches the predicate,
+    /// keeping the ones that don't match for later calls.
+    pub fn expect_raw<F: Fn(&str) -> bool>(&mut self, predicate: F) -> String {
+        if let Some(i) = self.pending.iter().position(|raw| predicate(raw)) {
+            return self.pending.remove(i);
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let raw = self.recv_raw();
+            if predicate(&raw) {
+                return raw;
+            }
+            self.pending.push(raw);
+        }
+        panic!(
+            "Client {} didn't receive the expected package, received: {:?}",
+            self.id, self.pending
+        );
+    }
+
+    /// Same as `expect_raw`, but the predicate checks the parsed package
+    pub fn expect<F: Fn(&Lnp) -> bool>(&mut self, predicate: F) -> Lnp {
+        let codec = self.codec;
+        let raw = self.expect_raw(|raw| predicate(&decode(codec, raw)));
+        decode(codec, &raw)
+    }
+
+    /// Whether a frame received but not consumed yet matches the predicate (*frames are
+    /// only read while waiting for another one, eg. in `command`*)
+    pub fn received<F: Fn(&Lnp) -> bool>(&self, predicate: F) -> bool {
+        self.pending
+            .iter()
+            .any(|raw| predicate(&decode(self.codec, raw)))
+    }
+
+    /// Sends a command and waits for its result package
+    pub fn command(&mut self, command: &str, arguments: &[&str]) -> Lnp {
+        self.request_id += 1;
+        let request_id = self.request_id;
+        self.send(msg_templates::client::command(
+            command.to_string(),
+            arguments.iter().map(|a| a.to_string()).collect(),
+            Some(request_id),
+        ));
+        self.expect(|pkg| {
+            msg_templates::command_name(pkg) == Some("result")
+                && matches!(pkg.content.get("request_id"), Some(Lpv::Int(i)) if *i == request_id)
+        })
+    }
+
+    /// Waits until the server closes the connection
+    pub fn expect_disconnected(&mut self) {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            match self.reader.read_frame() {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return,
+            }
+        }
+        panic!("Client {} is still connected.", self.id);
+    }
+}
+
+/// Package carried by a frame received, panicking if it isn't valid for the codec
+pub fn decode(codec: &dyn Codec, raw: &str) -> Lnp {
+    match codec.decode(raw.as_bytes()) {
+        Ok(pkg) => pkg,
+        Err(e) => panic!("Non valid {} package ({}): {}", codec.name(), e, raw),
+    }
+}
+
+/// Hello package of a client that supports every capability
+pub fn hello(nick: Option<String>) -> Lnp {
+    msg_templates::shared::hello(
+        "scripted".to_string(),
+        "0.0.0".to_string(),
+        capability::ALL.iter().map(|c| c.to_string()).collect(),
+        nick,
+    )
+}
+
+/// Integer value of the key specified
+pub fn int(pkg: &Lnp, key: &str) -> i128 {
+    match pkg.content.get(key) {
+        Some(Lpv::Int(i)) => *i,
+        other => panic!("Expected an integer in '{}', found {:?}", key, other),
+    }
+}
+
+/// Elements of the list in the key specified (*single element lists might be parsed
+/// as a plain value*)
+pub fn list(pkg: &Lnp, key: &str) -> Vec<String> {
+    match pkg.content.get(key) {
+        Some(Lpv::List(l)) => l.clone(),
+        Some(Lpv::Null) | None => vec![],
+        Some(other) => vec![other.to_string()],
+    }
+}
@@ -0,0 +1,42 @@
+use socks::history::History;
+
+#[test]
+fn bounded_history() {
+    let mut history = History::new(3);
+    for i in 1..=5 {
+        history.push(1, "lobby".to_string(), format!("message {}", i));
+    }
+
+    assert_eq!(3, history.len());
+    let msgs: Vec<&str> = history
+        .last(10, None, |_| true)
+        .iter()
+        .map(|entry| entry.msg.as_str())
+        .collect();
+    assert_eq!(vec!["message 3", "message 4", "message 5"], msgs);
+}
+
+#[test]
+fn paging() {
+    let mut history = History::new(10);
+    for i in 1..=6 {
+        let room = if i % 2 == 0 { "even" } else { "odd" };
+        history.push(1, room.to_string(), format!("message {}", i));
+    }
+
+    let ids: Vec<u64> = history
+        .last(2, Some(6), |entry| entry.room == "even")
+        .iter()
+        .map(|entry| entry.id)
+        .collect();
+    assert_eq!(vec![2, 4], ids);
+    assert!(history.last(5, Some(1), |_| true).is_empty());
+}
+
+#[test]
+fn disabled_history() {
+    let mut history = History::new(0);
+    let entry = history.push(1, "lobby".to_string(), "hi".to_string());
+    assert_eq!(1, entry.id);
+    assert!(history.is_empty());
+}
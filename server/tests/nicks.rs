@@ -0,0 +1,36 @@
+use socks::nicks::{same_nick, NickError, NickRules};
+
+#[test]
+fn nick_rules() {
+    let rules = NickRules {
+        min_length: 3,
+        max_length: 8,
+        symbols: "_".to_string(),
+        reserved: vec!["admin".to_string()],
+    };
+
+    assert_eq!(Ok(()), rules.check("some_one"));
+    assert_eq!(Ok(()), rules.check("ñandú"));
+    assert_eq!(Err(NickError::TooShort), rules.check("ab"));
+    assert_eq!(Err(NickError::TooLong), rules.check("someone_else"));
+    assert_eq!(
+        Err(NickError::NonValidCharacter(' ')),
+        rules.check("some one")
+    );
+    assert_eq!(Err(NickError::Reserved), rules.check("ADMIN"));
+}
+
+#[test]
+fn empty_nick() {
+    let rules = NickRules {
+        min_length: 0,
+        ..NickRules::default()
+    };
+    assert_eq!(Err(NickError::TooShort), rules.check(""));
+}
+
+#[test]
+fn case_insensitive() {
+    assert!(same_nick("Someone", "someONE"));
+    assert!(!same_nick("someone", "someone2"));
+}
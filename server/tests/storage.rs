@@ -0,0 +1,164 @@
+use socks::history::HistoryEntry;
+use socks::storage::{MemoryStorage, OfflineMessage, SqliteStorage, Storage};
+
+fn entry(id: u64, msg: &str) -> HistoryEntry {
+    HistoryEntry {
+        id,
+        timestamp: 1_700_000_000 + id,
+        author_id: 7,
+        room: "lobby".to_string(),
+        msg: msg.to_string(),
+    }
+}
+
+/// Checks that every storage backend behaves the same way
+fn check_storage(storage: &mut dyn Storage) {
+    assert_eq!(0, storage.last_message_id().unwrap());
+    assert_eq!(0, storage.load_last_client_id().unwrap());
+
+    for id in 1..=3 {
+        storage
+            .save_message(&entry(id, &format!("message {}", id)))
+            .unwrap();
+    }
+    assert_eq!(3, storage.last_message_id().unwrap());
+    assert_eq!(
+        vec![entry(2, "message 2"), entry(3, "message 3")],
+        storage.load_messages(2).unwrap()
+    );
+
+    storage.save_last_client_id(41).unwrap();
+    storage.save_last_client_id(42).unwrap();
+    assert_eq!(42, storage.load_last_client_id().unwrap());
+
+    storage.set_setting(1, "theme", "dark").unwrap();
+    storage.set_setting(1, "color", "red").unwrap();
+    storage.set_setting(1, "theme", "light").unwrap();
+    storage.set_setting(2, "theme", "dark").unwrap();
+    assert_eq!(
+        vec![
+            ("color".to_string(), "red".to_string()),
+            ("theme".to_string(), "light".to_string())
+        ],
+        storage.settings(1).unwrap()
+    );
+    assert!(storage.settings(3).unwrap().is_empty());
+
+    assert_eq!(None, storage.nick_secret("someone").unwrap());
+    storage.set_nick_secret("someone", "hash").unwrap();
+    assert_eq!(
+        Some("hash".to_string()),
+        storage.nick_secret("someone").unwrap()
+    );
+
+    let offline_message = |recipient: &str, msg: &str| OfflineMessage {
+        recipient: recipient.to_string(),
+        author_id: 7,
+        author_name: "author".to_string(),
+        timestamp: 1_700_000_000,
+        msg: msg.to_string(),
+    };
+    for (recipient, msg) in [
+        ("someone", "first"),
+        ("other", "x"),
+        ("someone", "second"),
+        ("someone", "third"),
+    ] {
+        storage
+            .queue_direct_message(&offline_message(recipient, msg))
+            .unwrap();
+    }
+    assert_eq!(
+        vec![
+            offline_message("someone", "first"),
+            offline_message("someone", "second")
+        ],
+        storage.take_direct_messages("someone", 2).unwrap()
+    );
+    // The ones over the limit are kept
+    assert_eq!(
+        vec![offline_message("someone", "third")],
+        storage.take_direct_messages("someone", usize::MAX).unwrap()
+    );
+    assert!(storage
+        .take_direct_messages("someone", usize::MAX)
+        .unwrap()
+        .is_empty());
+    assert!(storage.take_direct_messages("other", 0).unwrap().is_empty());
+    assert_eq!(1, storage.take_direct_messages("other", 1).unwrap().len());
+}
+
+#[test]
+fn memory_storage() {
+    check_storage(&mut MemoryStorage::default());
+}
+
+#[test]
+fn sqlite_storage() {
+    check_storage(&mut SqliteStorage::open_in_memory().unwrap());
+}
+
+#[test]
+fn sqlite_reopen() {
+    let path = std::env::temp_dir().join(format!("socks-storage-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut storage = SqliteStorage::open(&path).unwrap();
+    storage.save_message(&entry(1, "persisted")).unwrap();
+    storage.save_last_client_id(5).unwrap();
+    let version = storage.version().unwrap();
+    drop(storage);
+
+    // Migrations aren't applied twice
+    let storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(version, storage.version().unwrap());
+    assert_eq!(
+        vec![entry(1, "persisted")],
+        storage.load_messages(10).unwrap()
+    );
+    assert_eq!(5, storage.load_last_client_id().unwrap());
+    drop(storage);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn sqlite_nick_keys_migration() {
+    let path = std::env::temp_dir().join(format!("socks-nick-keys-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let version = SqliteStorage::open(&path).unwrap().version().unwrap();
+
+    // Nicknames stored as they were written, like the server used to do
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "
+            INSERT INTO nicks (nick, secret_hash) VALUES ('Alice', 'first'), ('ALICE', 'second'), ('Ünïcode', 'hash');
+            INSERT INTO offline_messages (recipient, author_id, author_name, timestamp, msg)
+                VALUES ('ALICE', 7, 'author', 1700000000, 'hi');
+            PRAGMA user_version = 2;
+            ",
+        )
+        .unwrap();
+    drop(connection);
+
+    let mut storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(version, storage.version().unwrap());
+    // The first one claimed keeps the nickname
+    assert_eq!(
+        Some("first".to_string()),
+        storage.nick_secret("alice").unwrap()
+    );
+    assert_eq!(
+        Some("hash".to_string()),
+        storage.nick_secret("ünïcode").unwrap()
+    );
+    assert_eq!(
+        1,
+        storage
+            .take_direct_messages("alice", usize::MAX)
+            .unwrap()
+            .len()
+    );
+    drop(storage);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,4 +1,5 @@
 use lnpkg;
+use msg_templates::framing;
 use std::collections::HashMap;
 #[test]
 fn test_exist() {
@@ -9,4 +10,40 @@ fn test_exist() {
 
     assert!(pkg.exist(&["exists", "stillexists"]));
     assert!(!pkg.exist(&[&"doesntexist"]));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_bind_and_shutdown() {
+    let handle = socks::ServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .build()
+        .unwrap()
+        .spawn();
+    assert_ne!(0, handle.local_addr().port());
+
+    let mut stream = std::net::TcpStream::connect(handle.local_addr()).unwrap();
+    let hello = msg_templates::shared::hello("test".to_string(), "0.0.0".to_string(), vec![], None);
+    framing::write_frame(&mut stream, hello.as_bytes().as_slice()).unwrap();
+    let mut reader = framing::FrameReader::new(stream, framing::DEFAULT_MAX_FRAME_SIZE);
+    // Welcome package
+    assert!(reader.read_frame().unwrap().is_some());
+
+    handle.shutdown();
+    // Connection closed by the server
+    while let Ok(Some(_)) = reader.read_frame() {}
+    assert!(handle.server().lock().unwrap().clients.is_empty());
+}
+
+#[test]
+fn test_build_invalid_config() {
+    let config = socks::config::Config {
+        heartbeat_interval: 0,
+        ..socks::config::Config::default()
+    };
+    let error = socks::ServerBuilder::from_config(config)
+        .bind("127.0.0.1:0")
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(std::io::ErrorKind::InvalidInput, error.kind());
+}